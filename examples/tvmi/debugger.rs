@@ -0,0 +1,473 @@
+//! A simple command-line debugger, used by `tvmi --debug`.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+use tinyvm::{
+    OutOfBounds, Register, RunOutcome, SourceLocation, StepStatus, Vm,
+    Watchpoint,
+};
+
+const HELP: &str = "Commands:
+  step, s [n]           Execute the next n instructions (default: 1)
+  continue, c           Run until the program halts or hits a breakpoint
+  break, b <target>     Set a breakpoint on a label, a line in the main
+                        file, or a file:line
+  watch, w <target>     Stop when a register or memory address changes
+  regs, r               Print the registers
+  mem, m <addr> <len>   Dump len bytes of memory, starting from addr
+  stack                 Print the values on the stack
+  disasm, d             Print the program's instructions
+  help, h               Print this message
+  quit, q               Exit the debugger";
+
+/// A command typed at the debugger's prompt.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Command<'a> {
+    Step(usize),
+    Continue,
+    Break(&'a str),
+    Watch(&'a str),
+    Registers,
+    Memory { address: usize, length: usize },
+    Stack,
+    Disassemble,
+    Help,
+    Quit,
+}
+
+impl<'a> Command<'a> {
+    /// Parse a line typed at the prompt, returning `None` if it was blank or
+    /// a message for the user if it isn't a valid command.
+    fn parse(line: &'a str) -> Result<Option<Command<'a>>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let command = match words.as_slice() {
+            [] => return Ok(None),
+            ["quit"] | ["q"] => Command::Quit,
+            ["help"] | ["h"] => Command::Help,
+            ["step"] | ["s"] => Command::Step(1),
+            ["step", n] | ["s", n] => match parse_number(n) {
+                Some(n) => Command::Step(n),
+                None => return Err(format!("\"{}\" isn't a number", n)),
+            },
+            ["continue"] | ["c"] => Command::Continue,
+            ["break", target] | ["b", target] => Command::Break(target),
+            ["watch", target] | ["w", target] => Command::Watch(target),
+            ["regs"] | ["r"] => Command::Registers,
+            ["mem", addr, len] | ["m", addr, len] => {
+                match (parse_number(addr), parse_number(len)) {
+                    (Some(address), Some(length)) => {
+                        Command::Memory { address, length }
+                    },
+                    _ => return Err(String::from("Usage: mem <addr> <len>")),
+                }
+            },
+            ["stack"] => Command::Stack,
+            ["disasm"] | ["d"] => Command::Disassemble,
+            _ => return Err(format!("Unknown command, \"{}\"", line.trim())),
+        };
+
+        Ok(Some(command))
+    }
+}
+
+pub struct Debugger {
+    vm: Vm,
+    /// The file passed to `tvmi`, used when breaking on a bare line number.
+    main_file: String,
+    /// The lines of each source file we've displayed so far.
+    sources: HashMap<String, Vec<String>>,
+    /// Has the program run to completion? The VM would start it again on
+    /// the next step, which isn't what someone debugging it expects.
+    halted: bool,
+}
+
+impl Debugger {
+    pub fn new(vm: Vm, main_file: String) -> Debugger {
+        Debugger {
+            vm,
+            main_file,
+            sources: HashMap::new(),
+            halted: false,
+        }
+    }
+
+    /// Read commands from stdin until the user quits or stdin is closed.
+    pub fn run(&mut self) -> io::Result<()> {
+        println!("Type \"help\" for a list of commands.");
+        self.print_current_instruction();
+
+        loop {
+            print!("(tvmi) ");
+            io::stdout().flush()?;
+
+            // stdin is only locked while reading a command, because the
+            // program's inp instruction reads from it too
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            match Command::parse(&line) {
+                Ok(Some(Command::Quit)) => return Ok(()),
+                Ok(Some(command)) => self.execute(command),
+                Ok(None) => {},
+                Err(msg) => println!("{}", msg),
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command<'_>) {
+        match command {
+            Command::Step(count) => self.step(count),
+            Command::Continue => self.resume(),
+            Command::Break(target) => self.add_breakpoint(target),
+            Command::Watch(target) => self.add_watchpoint(target),
+            Command::Registers => self.print_registers(),
+            Command::Memory { address, length } => {
+                self.dump_memory(address, length)
+            },
+            Command::Stack => self.print_stack(),
+            Command::Disassemble => self.disassemble(),
+            Command::Help => println!("{}", HELP),
+            Command::Quit => {},
+        }
+    }
+
+    fn step(&mut self, count: usize) {
+        if self.halted {
+            println!("The program has halted");
+            return;
+        }
+
+        for _ in 0..count {
+            match self.vm.step() {
+                StepStatus::Continued => {},
+                StepStatus::Halted => {
+                    self.halted = true;
+                    println!("The program halted");
+                    return;
+                },
+                StepStatus::Faulted(e) => {
+                    println!("{}", e);
+                    return;
+                },
+                StepStatus::WaitingForInput => {
+                    println!("Waiting for input");
+                    return;
+                },
+            }
+        }
+
+        self.print_current_instruction();
+    }
+
+    fn resume(&mut self) {
+        if self.halted {
+            println!("The program has halted");
+            return;
+        }
+
+        match self.vm.run() {
+            Ok(RunOutcome::Halted) => {
+                self.halted = true;
+                println!("The program halted");
+            },
+            Ok(RunOutcome::Breakpoint { eip }) => {
+                println!("Hit the breakpoint at instruction {}", eip);
+                self.print_current_instruction();
+            },
+            Ok(RunOutcome::Watchpoint {
+                watchpoint,
+                old_value,
+                new_value,
+                ..
+            }) => {
+                println!(
+                    "{} changed from {} to {}",
+                    describe(watchpoint),
+                    old_value,
+                    new_value
+                );
+                self.print_current_instruction();
+            },
+            Ok(other) => println!("Stopped: {:?}", other),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    fn add_breakpoint(&mut self, target: &str) {
+        if let Some(instruction) = self.vm.add_breakpoint_at_label(target) {
+            println!("Breakpoint set on instruction {}", instruction);
+            return;
+        }
+
+        let location = match target.rsplit_once(':') {
+            Some((file, line)) => {
+                parse_number(line).map(|line| SourceLocation::new(file, line))
+            },
+            None => parse_number(target)
+                .map(|line| SourceLocation::new(self.main_file.as_str(), line)),
+        };
+        let location = match location {
+            Some(loc) => loc,
+            None => {
+                println!("\"{}\" isn't a label or line number", target);
+                return;
+            },
+        };
+
+        match self.instruction_at(&location) {
+            Some(instruction) => {
+                self.vm.add_breakpoint(instruction);
+                println!("Breakpoint set on instruction {}", instruction);
+            },
+            None => println!("There are no instructions at {}", location),
+        }
+    }
+
+    /// Find the first instruction on or after a particular line.
+    fn instruction_at(&self, location: &SourceLocation) -> Option<usize> {
+        let line = self.vm.source_map().find(location)?;
+
+        self.vm
+            .instructions()
+            .iter()
+            .position(|instruction| instruction.line >= line)
+    }
+
+    fn add_watchpoint(&mut self, target: &str) {
+        let watchpoint = match target.parse::<Register>() {
+            Ok(reg) => Watchpoint::Register(reg),
+            Err(_) => match parse_number(target) {
+                Some(address) => Watchpoint::Memory(address),
+                None => {
+                    println!("\"{}\" isn't a register or address", target);
+                    return;
+                },
+            },
+        };
+
+        if self.vm.add_watchpoint(watchpoint) {
+            println!("Watching {}", describe(watchpoint));
+        } else {
+            println!("Unable to watch {}", describe(watchpoint));
+        }
+    }
+
+    fn print_registers(&self) {
+        for (i, &reg) in Register::ALL.iter().enumerate() {
+            let value = self.vm.reg(reg);
+            print!("{:>4}: {:#010x} {:<11}", reg, value, value);
+
+            if i % 3 == 2 {
+                println!();
+            }
+        }
+        println!();
+    }
+
+    fn dump_memory(&self, address: usize, length: usize) {
+        // read_bytes() does the same check, but we need to make sure the
+        // length is sensible before allocating a buffer for it
+        let memory_size = self.vm.memory_size();
+        match address.checked_add(length) {
+            Some(end) if end <= memory_size => {},
+            _ => {
+                let e = OutOfBounds {
+                    address,
+                    length,
+                    memory_size,
+                };
+                println!("{}", e);
+                return;
+            },
+        }
+
+        let mut buffer = vec![0; length];
+
+        if let Err(e) = self.vm.memory().read_bytes(address, &mut buffer) {
+            println!("{}", e);
+            return;
+        }
+
+        for (i, chunk) in buffer.chunks(16).enumerate() {
+            let bytes: Vec<String> =
+                chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+
+            println!(
+                "{:#010x}  {:<48} {}",
+                address + i * 16,
+                bytes.join(" "),
+                text
+            );
+        }
+    }
+
+    fn print_stack(&self) {
+        let esp = self.vm.reg(Register::Esp);
+        if esp < 0 {
+            println!("esp is invalid ({})", esp);
+            return;
+        }
+
+        let memory = self.vm.memory();
+        let mut address = esp as usize;
+
+        if address >= self.vm.stack_top() {
+            println!("The stack is empty");
+        }
+
+        while address < self.vm.stack_top() {
+            match memory.read_i32(address) {
+                Ok(value) => println!("{:#010x}: {}", address, value),
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                },
+            }
+            address += 4;
+        }
+    }
+
+    fn disassemble(&self) {
+        let eip = self.vm.next_eip();
+        let breakpoints: Vec<usize> = self.vm.breakpoints().collect();
+
+        for (i, instruction) in self.vm.instructions().iter().enumerate() {
+            let marker = if i as i32 == eip { "=>" } else { "  " };
+            let breakpoint = if breakpoints.contains(&i) { "*" } else { " " };
+            let location = self
+                .vm
+                .source_map()
+                .lookup(instruction.line)
+                .map(ToString::to_string)
+                .unwrap_or_default();
+
+            println!(
+                "{}{} {:>4}: {:<24} ; {}",
+                marker,
+                breakpoint,
+                i,
+                instruction.to_string(),
+                location
+            );
+        }
+    }
+
+    fn print_current_instruction(&mut self) {
+        let eip = self.vm.next_eip();
+        let instruction = match self.vm.instructions().get(eip as usize) {
+            Some(instruction) if eip >= 0 => instruction.clone(),
+            _ => return,
+        };
+
+        let location = self.vm.source_map().lookup(instruction.line).cloned();
+
+        match location {
+            Some(location) => {
+                let source = self.source_line(&location).unwrap_or_default();
+                println!(
+                    "{:>4}: {:<24} ; {}  {}",
+                    eip,
+                    instruction.to_string(),
+                    location,
+                    source.trim()
+                );
+            },
+            None => println!("{:>4}: {}", eip, instruction),
+        }
+    }
+
+    /// Get the text on a particular line of a source file.
+    fn source_line(&mut self, location: &SourceLocation) -> Option<String> {
+        if !self.sources.contains_key(&location.file) {
+            let text = std::fs::read_to_string(&location.file).ok()?;
+            let lines = text.lines().map(String::from).collect();
+            self.sources.insert(location.file.clone(), lines);
+        }
+
+        self.sources[&location.file]
+            .get(location.line.checked_sub(1)?)
+            .cloned()
+    }
+}
+
+fn describe(watchpoint: Watchpoint) -> String {
+    match watchpoint {
+        Watchpoint::Register(reg) => reg.to_string(),
+        Watchpoint::Memory(address) => format!("address {:#x}", address),
+    }
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal number.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("0x2a"), Some(42));
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("-1"), None);
+        assert_eq!(parse_number("forty-two"), None);
+    }
+
+    #[test]
+    fn parse_commands() {
+        let inputs = vec![
+            ("", None),
+            ("  \n", None),
+            ("s", Some(Command::Step(1))),
+            ("step 0x10\n", Some(Command::Step(16))),
+            ("c", Some(Command::Continue)),
+            ("b loop", Some(Command::Break("loop"))),
+            ("break main.vm:3", Some(Command::Break("main.vm:3"))),
+            ("w eax", Some(Command::Watch("eax"))),
+            ("regs", Some(Command::Registers)),
+            (
+                "m 0x100 16",
+                Some(Command::Memory {
+                    address: 256,
+                    length: 16,
+                }),
+            ),
+            ("stack", Some(Command::Stack)),
+            ("d", Some(Command::Disassemble)),
+            ("help", Some(Command::Help)),
+            ("q", Some(Command::Quit)),
+        ];
+
+        for (line, should_be) in inputs {
+            assert_eq!(Command::parse(line), Ok(should_be), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn report_invalid_commands() {
+        let inputs = vec![
+            ("step many", "\"many\" isn't a number"),
+            ("mem 0 x", "Usage: mem <addr> <len>"),
+            ("frobnicate 1 2\n", "Unknown command, \"frobnicate 1 2\""),
+            ("quit now", "Unknown command, \"quit now\""),
+        ];
+
+        for (line, should_be) in inputs {
+            assert_eq!(Command::parse(line), Err(String::from(should_be)));
+        }
+    }
+}
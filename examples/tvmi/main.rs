@@ -0,0 +1,214 @@
+mod debugger;
+
+use debugger::Debugger;
+use std::{env, fs::File, io, process};
+use tinyvm::{Profile, TraceFormat, Vm, VmBuilder};
+
+const USAGE: &str = "Usage: tvmi [--debug] [--memory <bytes>] [--trace] \
+                     [--trace-format <human|json>] [--profile] \
+                     [--profile-folded <path>] [--coverage <path>] \
+                     <filename>";
+
+/// The number of instructions listed in the profiling report.
+const HOT_SPOTS: usize = 10;
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(a) => a,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        },
+    };
+
+    let mut builder = VmBuilder::new();
+    if let Some(bytes) = args.memory_size {
+        builder = builder.memory_size(bytes);
+    }
+    if let Some(format) = args.trace {
+        builder = builder.trace_to(io::stderr(), format);
+    }
+    let mut vm = match builder.build() {
+        Ok(vm) => vm,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        },
+    };
+
+    if let Err(e) = vm.load(&args.filename) {
+        eprintln!("Unable to load \"{}\": {}", args.filename, e);
+        process::exit(1);
+    }
+
+    if args.debug {
+        let mut debugger = Debugger::new(vm, args.filename);
+        if let Err(e) = debugger.run() {
+            eprintln!("Debugger error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.profile || args.profile_folded.is_some() {
+        vm.start_profiling();
+    }
+
+    if args.coverage.is_some() {
+        vm.start_coverage();
+    }
+
+    let result = vm.run();
+
+    if let (Some(coverage), Some(path)) = (vm.coverage(), &args.coverage) {
+        let written = File::create(path).and_then(|f| coverage.write_lcov(f));
+        if let Err(e) = written {
+            eprintln!("Unable to write \"{}\": {}", path, e);
+        }
+    }
+
+    if let Some(profile) = vm.profile() {
+        if args.profile {
+            print_profile(&vm, profile);
+        }
+        if let Some(path) = &args.profile_folded {
+            let written =
+                File::create(path).and_then(|f| profile.write_folded(f));
+            if let Err(e) = written {
+                eprintln!("Unable to write \"{}\": {}", path, e);
+            }
+        }
+    }
+
+    if let Err(e) = result {
+        eprintln!("Error while running \"{}\": {}", args.filename, e);
+        process::exit(1);
+    }
+}
+
+fn print_profile(vm: &Vm, profile: &Profile) {
+    let total = profile.total().max(1) as f64;
+    let percent = |count: u64| count as f64 * 100.0 / total;
+
+    eprintln!("Executed {} instructions", profile.total());
+    eprintln!();
+    eprintln!("Hot spots:");
+    for hot_spot in profile.hot_spots(HOT_SPOTS) {
+        let instruction = &vm.instructions()[hot_spot.instruction];
+        let location = vm
+            .source_map()
+            .lookup(instruction.line)
+            .map(ToString::to_string)
+            .unwrap_or_default();
+
+        eprintln!(
+            "{:>12} {:>6.2}% {:>5}: {:<24} {}",
+            hot_spot.count,
+            percent(hot_spot.count),
+            hot_spot.instruction,
+            instruction.to_string(),
+            location
+        );
+    }
+
+    eprintln!();
+    eprintln!("Regions:");
+    for region in profile.regions() {
+        eprintln!(
+            "{:>12} {:>6.2}% {} ({}..{})",
+            region.count,
+            percent(region.count),
+            region.label.as_deref().unwrap_or("<no label>"),
+            region.start,
+            region.end
+        );
+    }
+}
+
+#[derive(Debug)]
+struct Args {
+    filename: String,
+    memory_size: Option<usize>,
+    debug: bool,
+    /// Write a trace of each executed instruction to stderr.
+    trace: Option<TraceFormat>,
+    /// Print a report of the most frequently executed code to stderr.
+    profile: bool,
+    /// Save the profile's call stacks in the folded stacks format.
+    profile_folded: Option<String>,
+    /// Save an lcov report of the lines and branches which were executed.
+    coverage: Option<String>,
+}
+
+impl Args {
+    fn parse<I>(args: I) -> Result<Args, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let mut filename = None;
+        let mut memory_size = None;
+        let mut debug = false;
+        let mut trace = None;
+        let mut profile = false;
+        let mut profile_folded = None;
+        let mut coverage = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--debug" => debug = true,
+                "--trace" => {
+                    trace = trace.or(Some(TraceFormat::Human));
+                },
+                "--trace-format" => {
+                    let value =
+                        args.next().ok_or("--trace-format requires a value")?;
+                    let format = match value.as_str() {
+                        "human" => TraceFormat::Human,
+                        "json" => TraceFormat::JsonLines,
+                        _ => {
+                            return Err(format!(
+                                "\"{}\" isn't a valid trace format",
+                                value
+                            ))
+                        },
+                    };
+                    trace = Some(format);
+                },
+                "--profile" => profile = true,
+                "--profile-folded" => {
+                    let path = args
+                        .next()
+                        .ok_or("--profile-folded requires a path")?;
+                    profile_folded = Some(path);
+                },
+                "--coverage" => {
+                    let path =
+                        args.next().ok_or("--coverage requires a path")?;
+                    coverage = Some(path);
+                },
+                "--memory" => {
+                    let value =
+                        args.next().ok_or("--memory requires a value")?;
+                    let bytes = value.parse().map_err(|_| {
+                        format!("\"{}\" isn't a valid memory size", value)
+                    })?;
+                    memory_size = Some(bytes);
+                },
+                _ if filename.is_none() => filename = Some(arg),
+                _ => return Err(format!("Unexpected argument, \"{}\"", arg)),
+            }
+        }
+
+        Ok(Args {
+            filename: filename.ok_or("No file provided")?,
+            memory_size,
+            debug,
+            trace,
+            profile,
+            profile_folded,
+            coverage,
+        })
+    }
+}
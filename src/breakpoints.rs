@@ -0,0 +1,225 @@
+use crate::{Memory, Register};
+use std::collections::{BTreeSet, VecDeque};
+
+/// Something which can be watched for changes while the VM is running.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Watchpoint {
+    Register(Register),
+    /// The 32-bit integer at a particular byte address.
+    Memory(usize),
+}
+
+impl Watchpoint {
+    /// Read the current value, returning `None` if the address is invalid.
+    fn read(self, registers: &[i32], memory: Memory<'_>) -> Option<i32> {
+        match self {
+            Watchpoint::Register(reg) => Some(registers[reg as usize]),
+            Watchpoint::Memory(address) => memory.read_i32(address).ok(),
+        }
+    }
+}
+
+/// A watchpoint which triggered because its value changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Change {
+    /// The index of the instruction which changed the value.
+    pub(crate) eip: usize,
+    pub(crate) watchpoint: Watchpoint,
+    pub(crate) old_value: i32,
+    pub(crate) new_value: i32,
+}
+
+/// The breakpoints and watchpoints set on a VM.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Breakpoints {
+    instructions: BTreeSet<usize>,
+    /// Each watchpoint and the last value we saw.
+    watchpoints: Vec<(Watchpoint, i32)>,
+    /// Changes which were found at the same time as another change and
+    /// haven't been reported yet.
+    pending: VecDeque<Change>,
+}
+
+impl Breakpoints {
+    pub(crate) fn add(&mut self, instruction: usize) -> bool {
+        self.instructions.insert(instruction)
+    }
+
+    pub(crate) fn remove(&mut self, instruction: usize) -> bool {
+        self.instructions.remove(&instruction)
+    }
+
+    pub(crate) fn contains(&self, instruction: usize) -> bool {
+        self.instructions.contains(&instruction)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.instructions.iter().copied()
+    }
+
+    /// Start watching something, returning `false` if it can't be read.
+    pub(crate) fn watch(
+        &mut self,
+        watchpoint: Watchpoint,
+        registers: &[i32],
+        memory: Memory<'_>,
+    ) -> bool {
+        let value = match watchpoint.read(registers, memory) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        match self.watchpoints.iter_mut().find(|(w, _)| *w == watchpoint) {
+            Some(existing) => existing.1 = value,
+            None => self.watchpoints.push((watchpoint, value)),
+        }
+
+        true
+    }
+
+    pub(crate) fn unwatch(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|(w, _)| *w != watchpoint);
+        self.pending
+            .retain(|change| change.watchpoint != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub(crate) fn watchpoints(&self) -> impl Iterator<Item = Watchpoint> + '_ {
+        self.watchpoints.iter().map(|(w, _)| *w)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.instructions.clear();
+        self.watchpoints.clear();
+        self.pending.clear();
+    }
+
+    /// Remember the current value of everything being watched, so changes
+    /// made while the VM was stopped aren't reported.
+    ///
+    /// Changes which are still waiting to be reported by
+    /// [`Breakpoints::next_pending()`] are discarded.
+    pub(crate) fn refresh(&mut self, registers: &[i32], memory: Memory<'_>) {
+        self.pending.clear();
+
+        for (watchpoint, last_value) in &mut self.watchpoints {
+            if let Some(value) = watchpoint.read(registers, memory) {
+                *last_value = value;
+            }
+        }
+    }
+
+    /// Check whether any watched values have changed since we last looked,
+    /// after executing the instruction at `eip`.
+    ///
+    /// If more than one value changed, the first change is returned and the
+    /// rest are saved for [`Breakpoints::next_pending()`].
+    pub(crate) fn changes(
+        &mut self,
+        eip: usize,
+        registers: &[i32],
+        memory: Memory<'_>,
+    ) -> Option<Change> {
+        for (watchpoint, last_value) in &mut self.watchpoints {
+            match watchpoint.read(registers, memory) {
+                Some(value) if value != *last_value => {
+                    self.pending.push_back(Change {
+                        eip,
+                        watchpoint: *watchpoint,
+                        old_value: *last_value,
+                        new_value: value,
+                    });
+                    *last_value = value;
+                },
+                _ => {},
+            }
+        }
+
+        self.pending.pop_front()
+    }
+
+    /// Take the next change which was found by [`Breakpoints::changes()`]
+    /// but hasn't been reported yet.
+    pub(crate) fn next_pending(&mut self) -> Option<Change> {
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::PagedMemory, MemoryMut};
+
+    #[test]
+    fn detect_changes() {
+        let mut registers = [0; Register::ALL.len()];
+        let mut memory = PagedMemory::new(8);
+        let mut breakpoints = Breakpoints::default();
+        let eax = Watchpoint::Register(Register::Eax);
+        let cell = Watchpoint::Memory(4);
+        assert!(breakpoints.watch(eax, &registers, Memory::new(&memory)));
+        assert!(breakpoints.watch(cell, &registers, Memory::new(&memory)));
+        assert!(!breakpoints.watch(
+            Watchpoint::Memory(6),
+            &registers,
+            Memory::new(&memory)
+        ));
+
+        assert_eq!(
+            breakpoints.changes(0, &registers, Memory::new(&memory)),
+            None
+        );
+
+        MemoryMut::new(&mut memory).write_bytes(4, &[42]).unwrap();
+        let got = breakpoints.changes(1, &registers, Memory::new(&memory));
+        assert_eq!(
+            got,
+            Some(Change {
+                eip: 1,
+                watchpoint: cell,
+                old_value: 0,
+                new_value: i32::from_ne_bytes([42, 0, 0, 0]),
+            })
+        );
+        // the change is only reported once
+        assert_eq!(
+            breakpoints.changes(2, &registers, Memory::new(&memory)),
+            None
+        );
+
+        registers[Register::Eax as usize] = 1;
+        breakpoints.refresh(&registers, Memory::new(&memory));
+        assert_eq!(
+            breakpoints.changes(3, &registers, Memory::new(&memory)),
+            None
+        );
+    }
+
+    #[test]
+    fn queue_simultaneous_changes() {
+        let mut registers = [0; Register::ALL.len()];
+        let memory = PagedMemory::new(8);
+        let mut breakpoints = Breakpoints::default();
+        let esp = Watchpoint::Register(Register::Esp);
+        let eax = Watchpoint::Register(Register::Eax);
+        assert!(breakpoints.watch(esp, &registers, Memory::new(&memory)));
+        assert!(breakpoints.watch(eax, &registers, Memory::new(&memory)));
+
+        registers[Register::Esp as usize] = 4;
+        registers[Register::Eax as usize] = 7;
+        let first = breakpoints.changes(5, &registers, Memory::new(&memory));
+
+        assert_eq!(first.map(|c| c.watchpoint), Some(esp));
+        assert_eq!(
+            breakpoints.next_pending(),
+            Some(Change {
+                eip: 5,
+                watchpoint: eax,
+                old_value: 0,
+                new_value: 7,
+            })
+        );
+        assert_eq!(breakpoints.next_pending(), None);
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A thread-safe handle which can be used to stop a running [`crate::Vm`]
+/// from somewhere else (e.g. another thread, or when a request is
+/// cancelled).
+///
+/// Cancellation is sticky. Once [`CancellationToken::cancel()`] has been
+/// called, every VM using this token will stop the next time it checks.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self { CancellationToken::default() }
+
+    /// Ask any VMs using this token to stop.
+    pub fn cancel(&self) { self.0.store(true, Ordering::SeqCst); }
+
+    pub fn is_cancelled(&self) -> bool { self.0.load(Ordering::SeqCst) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn cancel_from_another_thread() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+
+        thread::spawn(move || clone.cancel()).join().unwrap();
+
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{program::Program, Opcode, SourceLocation};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+/// Records which instructions and branches were executed, so they can be
+/// mapped back to the original source code.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Coverage {
+    /// The number of times each instruction was executed.
+    hits: Vec<u64>,
+    /// Where each instruction came from, if the source map knows.
+    locations: Vec<Option<SourceLocation>>,
+    /// Taken/not-taken counts for each conditional jump.
+    branches: BTreeMap<usize, Branch>,
+}
+
+impl Coverage {
+    pub(crate) fn new(program: &Program) -> Coverage {
+        let locations = program
+            .instructions
+            .iter()
+            .map(|instruction| {
+                program.source_map.lookup(instruction.line).cloned()
+            })
+            .collect();
+        let branches = program
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| is_conditional_jump(instruction.opcode))
+            .map(|(index, _)| (index, Branch::default()))
+            .collect();
+
+        Coverage {
+            hits: vec![0; program.len()],
+            locations,
+            branches,
+        }
+    }
+
+    /// Record that the instruction at `eip` was executed, leaving `eip`
+    /// pointing at `next`.
+    pub(crate) fn record(&mut self, eip: usize, next: usize) {
+        self.hits[eip] += 1;
+
+        if let Some(branch) = self.branches.get_mut(&eip) {
+            // a jump to the very next instruction looks the same as falling
+            // through, so it's counted as not taken
+            if next == eip + 1 {
+                branch.not_taken += 1;
+            } else {
+                branch.taken += 1;
+            }
+        }
+    }
+
+    /// How many times the instruction at `index` was executed.
+    pub fn hits(&self, index: usize) -> u64 {
+        self.hits.get(index).copied().unwrap_or(0)
+    }
+
+    /// How often the conditional jump at `index` was taken, or `None` if it
+    /// isn't a conditional jump.
+    pub fn branch(&self, index: usize) -> Option<Branch> {
+        self.branches.get(&index).copied()
+    }
+
+    /// The number of times each source line was executed.
+    ///
+    /// When one line produced several instructions (e.g. because the file
+    /// was included more than once) the largest count is used.
+    pub fn lines(&self) -> BTreeMap<SourceLocation, u64> {
+        let mut lines = BTreeMap::new();
+
+        for (location, &hits) in self.locations.iter().zip(&self.hits) {
+            let location = match location {
+                Some(location) => location,
+                None => continue,
+            };
+            let count = lines.entry(location.clone()).or_insert(0);
+            *count = u64::max(*count, hits);
+        }
+
+        lines
+    }
+
+    /// Write an [lcov] tracefile, with line and branch coverage for each
+    /// source file.
+    ///
+    /// [lcov]: https://ltp.sourceforge.net/coverage/lcov/geninfo.1.php
+    pub fn write_lcov<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let lines = self.lines();
+        let mut files: BTreeMap<&str, Vec<(usize, u64)>> = BTreeMap::new();
+        for (location, hits) in &lines {
+            files
+                .entry(location.file.as_str())
+                .or_default()
+                .push((location.line, *hits));
+        }
+
+        writeln!(writer, "TN:")?;
+
+        for (file, file_lines) in files {
+            writeln!(writer, "SF:{}", file)?;
+
+            let mut found = 0;
+            let mut hit = 0;
+
+            for (&index, branch) in &self.branches {
+                let location = match &self.locations[index] {
+                    Some(location) if location.file == file => location,
+                    _ => continue,
+                };
+                let executed = self.hits[index] > 0;
+
+                for (number, &count) in
+                    [branch.taken, branch.not_taken].iter().enumerate()
+                {
+                    found += 1;
+                    if count > 0 {
+                        hit += 1;
+                    }
+                    if executed {
+                        writeln!(
+                            writer,
+                            "BRDA:{},{},{},{}",
+                            location.line, index, number, count
+                        )?;
+                    } else {
+                        writeln!(
+                            writer,
+                            "BRDA:{},{},{},-",
+                            location.line, index, number
+                        )?;
+                    }
+                }
+            }
+
+            writeln!(writer, "BRF:{}", found)?;
+            writeln!(writer, "BRH:{}", hit)?;
+
+            for &(line, hits) in &file_lines {
+                writeln!(writer, "DA:{},{}", line, hits)?;
+            }
+
+            let lines_hit = file_lines.iter().filter(|(_, h)| *h > 0).count();
+            writeln!(writer, "LF:{}", file_lines.len())?;
+            writeln!(writer, "LH:{}", lines_hit)?;
+            writeln!(writer, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The number of times a conditional jump was (or wasn't) taken.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+fn is_conditional_jump(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Je
+            | Opcode::Jne
+            | Opcode::Jg
+            | Opcode::Jge
+            | Opcode::Jl
+            | Opcode::Jle
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HashTable, SourceMap};
+
+    #[test]
+    fn write_an_lcov_report() {
+        let src = "start:\n  cmp eax, 0\n  je end\n  prn eax\nend:\n";
+        let mut program = crate::parser::parse(src, &HashTable::new()).unwrap();
+        program.source_map = SourceMap::new("main.vm", src);
+        let mut coverage = Coverage::new(&program);
+
+        coverage.record(0, 1);
+        coverage.record(1, 3);
+
+        assert_eq!(coverage.hits(1), 1);
+        assert_eq!(coverage.hits(2), 0);
+        assert_eq!(
+            coverage.branch(1),
+            Some(Branch {
+                taken: 1,
+                not_taken: 0
+            })
+        );
+        assert_eq!(coverage.branch(0), None);
+
+        let mut lcov = Vec::new();
+        coverage.write_lcov(&mut lcov).unwrap();
+        let expected = "TN:\nSF:main.vm\nBRDA:3,1,0,1\nBRDA:3,1,1,0\n\
+                        BRF:2\nBRH:1\nDA:2,1\nDA:3,1\nDA:4,0\nLF:3\nLH:2\n\
+                        end_of_record\n";
+        assert_eq!(String::from_utf8(lcov).unwrap(), expected);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::{memory::PagedMemory, Memory, MemoryMut, Register};
+use std::error::Error;
+
+/// The error a host function can return to abort execution.
+pub type HostError = Box<dyn Error + Send + Sync>;
+
+/// A host function which can be invoked from TinyVM assembly using
+/// `int <number>`.
+///
+/// Host functions must be `Send` so the [`crate::Vm`] they are registered
+/// with can be moved to another thread.
+pub type HostFunction =
+    Box<dyn FnMut(&mut HostContext<'_>) -> Result<(), HostError> + Send>;
+
+/// The VM state a host function is allowed to access.
+///
+/// By convention, arguments are passed in registers (starting with `eax`)
+/// and results are written back to `eax`.
+#[derive(Debug)]
+pub struct HostContext<'vm> {
+    registers: &'vm mut [i32],
+    memory: &'vm mut PagedMemory,
+}
+
+impl<'vm> HostContext<'vm> {
+    pub(crate) fn new(
+        registers: &'vm mut [i32],
+        memory: &'vm mut PagedMemory,
+    ) -> Self {
+        HostContext { registers, memory }
+    }
+
+    /// Read the contents of a register.
+    pub fn reg(&self, register: Register) -> i32 {
+        self.registers[register as usize]
+    }
+
+    /// Overwrite the contents of a register.
+    ///
+    /// Setting `eip` jumps to that instruction once the host function
+    /// returns.
+    pub fn set_reg(&mut self, register: Register, value: i32) {
+        self.registers[register as usize] = value;
+    }
+
+    /// Get a read-only view of the VM's memory.
+    pub fn memory(&self) -> Memory<'_> { Memory::new(self.memory) }
+
+    /// Get a mutable view of the VM's memory.
+    pub fn memory_mut(&mut self) -> MemoryMut<'_> {
+        MemoryMut::new(self.memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_registers_and_memory() {
+        let mut registers = [0; Register::ALL.len()];
+        let mut memory = PagedMemory::new(8);
+        let mut ctx = HostContext::new(&mut registers, &mut memory);
+
+        ctx.set_reg(Register::Eax, 42);
+        let eax = ctx.reg(Register::Eax);
+        ctx.memory_mut().write_i32(4, eax).unwrap();
+
+        assert_eq!(ctx.memory().read_i32(4).unwrap(), 42);
+        assert_eq!(registers[Register::Eax as usize], 42);
+    }
+}
@@ -1,72 +1,374 @@
 use std::{
-    collections::HashMap,
+    collections::{
+        btree_map::{self, Entry},
+        BTreeMap,
+    },
+    error::Error,
     ffi::{CStr, CString},
+    fmt::{self, Debug, Display, Formatter},
     os::raw::{c_char, c_int, c_void},
     ptr,
 };
 
+/// A table mapping names to integer and/or opaque values, used by `libtvm`
+/// to store things like labels and `%define`s.
+///
+/// Entries are kept sorted by key so iteration order is deterministic.
+///
+/// With the `serde` feature enabled, a table (e.g. the `%define`s produced by
+/// [`crate::preprocess()`]) can be serialized as a map from each key to its
+/// tagged value:
+///
+/// ```json
+/// { "start": { "int": 0 }, "FOO": { "bytes": "bar" } }
+/// ```
 #[derive(Debug, Default, Clone, PartialEq)]
-pub struct HashTable(pub(crate) HashMap<CString, Item>);
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct HashTable(pub(crate) BTreeMap<String, Item>);
 
+impl HashTable {
+    pub fn new() -> HashTable { HashTable::default() }
+
+    /// The number of entries in the table.
+    pub fn len(&self) -> usize { self.0.len() }
+
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+
+    pub fn contains(&self, key: &str) -> bool { self.0.contains_key(key) }
+
+    /// Associate an integer with `key`, returning the previous entry (if any).
+    ///
+    /// Unlike [`tvm_htab_add()`], this replaces the whole entry instead of
+    /// keeping any opaque value which was already there.
+    pub fn insert_int(&mut self, key: &str, value: c_int) -> Option<Item> {
+        self.0.insert(key.to_string(), Item::integer(value))
+    }
+
+    /// Associate a string with `key`, returning the previous entry (if any).
+    ///
+    /// This is equivalent to the `tvm_htab_add_ref()` calls the preprocessor
+    /// makes for each `%define`.
+    pub fn insert_str(&mut self, key: &str, value: &str) -> Option<Item> {
+        self.0.insert(key.to_string(), Item::opaque(value))
+    }
+
+    /// Look up the entry associated with `key`.
+    ///
+    /// Use [`Item::as_int()`] or [`Item::as_bytes()`] to find out whether it
+    /// holds the kind of value you are expecting.
+    pub fn get(&self, key: &str) -> Option<&Item> { self.0.get(key) }
+
+    /// Get the integer associated with `key`, returning `None` if there is no
+    /// such entry or it doesn't hold an integer.
+    pub fn get_int(&self, key: &str) -> Option<c_int> {
+        self.get(key).and_then(|item| item.as_int().ok())
+    }
+
+    /// Get the string associated with `key`, returning `None` if there is no
+    /// such entry or its value isn't valid UTF-8.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(Item::opaque_value_str)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Item> { self.0.remove(key) }
+
+    /// Iterate over every entry in the table, sorted by key.
+    pub fn iter(&self) -> Iter<'_> { Iter(self.0.iter()) }
+}
+
+impl<'a> IntoIterator for &'a HashTable {
+    type IntoIter = Iter<'a>;
+    type Item = (&'a str, &'a Item);
+
+    fn into_iter(self) -> Iter<'a> { self.iter() }
+}
+
+/// An iterator over the entries in a [`HashTable`].
+#[derive(Debug, Clone)]
+pub struct Iter<'a>(btree_map::Iter<'a, String, Item>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a Item);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, item)| (key.as_str(), item))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.0.size_hint() }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+/// A single entry in a [`HashTable`].
+///
+/// `libtvm` lets you attach an integer (via [`tvm_htab_add()`]) and an opaque
+/// value (via [`tvm_htab_add_ref()`]) to the same key, so an entry may hold
+/// either kind of value or both.
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct Item {
-    /// An integer value.
-    value: c_int,
-    /// An opaque value used with [`tvm_htab_add_ref()`].
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Item {
+    /// An integer value, typically a label's instruction index.
+    Int(c_int),
+    /// An opaque value, typically the text a `%define` expands to.
+    Bytes(Opaque),
+    /// An entry with both an integer and an opaque value.
+    Both { int: c_int, bytes: Opaque },
+}
+
+impl Item {
+    pub(crate) fn integer(value: c_int) -> Item { Item::Int(value) }
+
+    pub(crate) fn opaque<V>(opaque_value: V) -> Item
+    where
+        V: Into<Vec<u8>>,
+    {
+        Item::Bytes(Opaque::new(opaque_value))
+    }
+
+    pub(crate) fn from_void(pointer: *mut c_void, length: c_int) -> Item {
+        Item::Bytes(copy_from_void(pointer, length))
+    }
+
+    /// The integer value.
+    pub fn as_int(&self) -> Result<c_int, TypeMismatch> {
+        match *self {
+            Item::Int(int) | Item::Both { int, .. } => Ok(int),
+            Item::Bytes(_) => Err(TypeMismatch {
+                expected: ValueKind::Int,
+                found: ValueKind::Bytes,
+            }),
+        }
+    }
+
+    /// The opaque value, as a sequence of bytes.
+    pub fn as_bytes(&self) -> Result<&[u8], TypeMismatch> {
+        match self {
+            Item::Bytes(bytes) | Item::Both { bytes, .. } => {
+                Ok(bytes.as_bytes())
+            },
+            Item::Int(_) => Err(TypeMismatch {
+                expected: ValueKind::Bytes,
+                found: ValueKind::Int,
+            }),
+        }
+    }
+
+    /// The opaque value, if there is one and it is valid UTF-8.
+    pub fn opaque_value_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .ok()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    /// Set the integer value, keeping any opaque value which is already
+    /// present (mirrors `tvm_htab_add()` on an existing key).
+    fn set_int(&mut self, value: c_int) {
+        *self = match std::mem::replace(self, Item::Int(value)) {
+            Item::Bytes(bytes) | Item::Both { bytes, .. } => {
+                Item::Both { int: value, bytes }
+            },
+            Item::Int(_) => Item::Int(value),
+        };
+    }
+
+    /// Set the opaque value, keeping any integer value which is already
+    /// present (mirrors `tvm_htab_add_ref()` on an existing key).
+    fn set_bytes(&mut self, bytes: Opaque) {
+        *self = match *self {
+            Item::Int(int) | Item::Both { int, .. } => {
+                Item::Both { int, bytes }
+            },
+            Item::Bytes(_) => Item::Bytes(bytes),
+        };
+    }
+}
+
+/// An owned byte buffer which is always followed by a NUL terminator, so it
+/// can be handed to C code expecting a `char *`.
+///
+/// Unlike a [`std::ffi::CString`], the contents may contain interior NUL
+/// bytes. Code which needs the full value should use
+/// [`tvm_htab_find_ref_len()`] to get its length instead of calling
+/// `strlen()`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Opaque {
+    /// The value's bytes, followed by a NUL terminator.
     ///
     /// # Safety
     ///
     /// Storing the contents of a `void *` in a `Vec<u8>` *would* normally
     /// result in alignment issues, but we've got access to the `libtvm` source
     /// code and know it will only ever store `char *` strings.
-    opaque_value: Vec<u8>,
+    bytes_with_nul: Vec<u8>,
 }
 
-impl Item {
-    pub(crate) fn integer(value: c_int) -> Item {
-        Item {
-            value,
-            opaque_value: Vec::new(),
-        }
+impl Opaque {
+    pub fn new<B: Into<Vec<u8>>>(bytes: B) -> Opaque {
+        let mut bytes_with_nul = bytes.into();
+        bytes_with_nul.push(0);
+
+        Opaque { bytes_with_nul }
     }
 
-    pub(crate) fn opaque<V>(opaque_value: V) -> Item
-    where
-        V: Into<Vec<u8>>,
-    {
-        Item {
-            value: 0,
-            opaque_value: opaque_value.into(),
+    /// The value, without its NUL terminator.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes_with_nul[..self.bytes_with_nul.len() - 1]
+    }
+
+    pub fn as_bytes_with_nul(&self) -> &[u8] { &self.bytes_with_nul }
+
+    /// A pointer to the value which is safe to pass to `strlen()`.
+    pub fn as_ptr(&self) -> *const c_char {
+        self.bytes_with_nul.as_ptr().cast()
+    }
+
+    /// The length of the value, not including the NUL terminator.
+    pub fn len(&self) -> usize { self.bytes_with_nul.len() - 1 }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl Debug for Opaque {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Opaque")
+            .field(&String::from_utf8_lossy(self.as_bytes()))
+            .finish()
+    }
+}
+
+impl From<&str> for Opaque {
+    fn from(s: &str) -> Opaque { Opaque::new(s) }
+}
+
+impl From<Vec<u8>> for Opaque {
+    fn from(bytes: Vec<u8>) -> Opaque { Opaque::new(bytes) }
+}
+
+impl AsRef<[u8]> for Opaque {
+    fn as_ref(&self) -> &[u8] { self.as_bytes() }
+}
+
+/// Opaque values are serialized as strings when they are valid UTF-8 (which
+/// is almost always the case for `%define`s), falling back to a sequence of
+/// bytes otherwise.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Opaque {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        ser: S,
+    ) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(self.as_bytes()) {
+            Ok(s) => ser.serialize_str(s),
+            Err(_) => ser.serialize_bytes(self.as_bytes()),
         }
     }
+}
 
-    pub(crate) fn from_void(pointer: *mut c_void, length: c_int) -> Item {
-        // we need to create an owned copy of the value
-        let opaque_value = if pointer.is_null() {
-            Vec::new()
-        } else {
-            unsafe {
-                std::slice::from_raw_parts(pointer as *mut u8, length as usize)
-                    .to_owned()
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Opaque {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        de: D,
+    ) -> Result<Opaque, D::Error> {
+        struct OpaqueVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for OpaqueVisitor {
+            type Value = Opaque;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str("a string or a sequence of bytes")
             }
-        };
 
-        Item::opaque(opaque_value)
+            fn visit_str<E>(self, s: &str) -> Result<Opaque, E> { Ok(s.into()) }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Opaque, E> {
+                Ok(Opaque::new(bytes))
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Opaque, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let mut bytes = Vec::new();
+
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+
+                Ok(Opaque::new(bytes))
+            }
+        }
+
+        de.deserialize_any(OpaqueVisitor)
     }
+}
 
-    pub(crate) fn opaque_value(&self) -> &[u8] { &self.opaque_value }
+/// The different kinds of value an [`Item`] can hold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValueKind {
+    Int,
+    Bytes,
+}
 
-    pub(crate) fn opaque_value_str(&self) -> Option<&str> {
-        std::str::from_utf8(self.opaque_value()).ok()
+impl Display for ValueKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueKind::Int => write!(f, "an integer"),
+            ValueKind::Bytes => write!(f, "opaque bytes"),
+        }
     }
 }
 
+/// The error returned when an [`Item`] doesn't contain the kind of value that
+/// was asked for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub expected: ValueKind,
+    pub found: ValueKind,
+}
+
+impl Display for TypeMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected {} but found {}", self.expected, self.found)
+    }
+}
+
+impl Error for TypeMismatch {}
+
+fn copy_from_void(pointer: *mut c_void, length: c_int) -> Opaque {
+    // we need to create an owned copy of the value
+    if pointer.is_null() {
+        Opaque::new(Vec::new())
+    } else {
+        unsafe {
+            Opaque::new(std::slice::from_raw_parts(
+                pointer as *mut u8,
+                length as usize,
+            ))
+        }
+    }
+}
+
+/// Create a new, empty [`HashTable`].
+///
+/// Returns a null pointer if the table couldn't be created.
 #[no_mangle]
 pub unsafe extern "C" fn tvm_htab_create() -> *mut HashTable {
-    let hashtable = Box::new(HashTable::default());
-    Box::into_raw(hashtable)
+    crate::catch_panic(ptr::null_mut(), || {
+        let hashtable = Box::new(HashTable::default());
+        Box::into_raw(hashtable)
+    })
 }
 
+/// Free a [`HashTable`] created by [`tvm_htab_create()`]. Passing a null
+/// pointer is a no-op.
 #[no_mangle]
 pub unsafe extern "C" fn tvm_htab_destroy(htab: *mut HashTable) {
     if htab.is_null() {
@@ -74,28 +376,55 @@ pub unsafe extern "C" fn tvm_htab_destroy(htab: *mut HashTable) {
         return;
     }
 
-    let hashtable = Box::from_raw(htab);
-    // explicitly destroy the hashtable
-    drop(hashtable);
+    crate::catch_panic((), || {
+        let hashtable = Box::from_raw(htab);
+        // explicitly destroy the hashtable
+        drop(hashtable);
+    })
 }
 
+/// Associate an integer with `key`.
+///
+/// Returns `0` on success, or `-1` if either pointer is null or the key isn't
+/// valid UTF-8.
+///
+/// Unlike the original C implementation, which accepted any sequence of
+/// non-NUL bytes, keys must be valid UTF-8 because [`HashTable`] stores them
+/// as `String`s.
 #[no_mangle]
 pub unsafe extern "C" fn tvm_htab_add(
     htab: *mut HashTable,
     key: *const c_char,
     value: c_int,
 ) -> c_int {
-    let hashtable = &mut *htab;
-    let key = CStr::from_ptr(key).to_owned();
+    if htab.is_null() || key.is_null() {
+        return -1;
+    }
 
-    hashtable.0.insert(key, Item::integer(value));
+    crate::catch_panic(-1, || {
+        let hashtable = &mut *htab;
+        let key = match CStr::from_ptr(key).to_str() {
+            Ok(k) => k.to_string(),
+            // keys must be valid UTF-8
+            Err(_) => return -1,
+        };
+
+        match hashtable.0.entry(key) {
+            Entry::Occupied(mut entry) => entry.get_mut().set_int(value),
+            Entry::Vacant(entry) => {
+                entry.insert(Item::integer(value));
+            },
+        }
 
-    // the only time insertion can fail is if allocation fails. In that case
-    // we'll abort the process anyway, so if this function returns we can
-    // assume it was successful (0 = success).
-    0
+        0
+    })
 }
 
+/// Associate a copy of the `length` bytes at `value_ptr` with `key`. A null
+/// `value_ptr` is stored as an empty value.
+///
+/// Returns `0` on success, or `-1` if `htab` or `key` are null, the key isn't
+/// valid UTF-8 (see [`tvm_htab_add()`]), or `length` is negative.
 #[no_mangle]
 pub unsafe extern "C" fn tvm_htab_add_ref(
     htab: *mut HashTable,
@@ -103,38 +432,514 @@ pub unsafe extern "C" fn tvm_htab_add_ref(
     value_ptr: *mut c_void,
     length: c_int,
 ) -> c_int {
-    let hashtable = &mut *htab;
-    let key = CStr::from_ptr(key).to_owned();
+    if htab.is_null() || key.is_null() || length < 0 {
+        return -1;
+    }
 
-    hashtable.0.insert(key, Item::from_void(value_ptr, length));
+    crate::catch_panic(-1, || {
+        let hashtable = &mut *htab;
+        let key = match CStr::from_ptr(key).to_str() {
+            Ok(k) => k.to_string(),
+            Err(_) => return -1,
+        };
+
+        match hashtable.0.entry(key) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().set_bytes(copy_from_void(value_ptr, length))
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(Item::from_void(value_ptr, length));
+            },
+        }
 
-    0
+        0
+    })
 }
 
+/// Look up the integer associated with `key`.
+///
+/// Returns `-1` if there is no such entry, the entry doesn't hold an integer
+/// (e.g. a `%define`), the key isn't valid UTF-8, or either pointer is null.
+///
+/// Because `-1` is also a valid integer value, this can't tell a missing
+/// entry apart from one holding `-1`. Use [`tvm_htab_find_checked()`] when
+/// that matters.
 #[no_mangle]
 pub unsafe extern "C" fn tvm_htab_find(
     htab: *mut HashTable,
     key: *const c_char,
 ) -> c_int {
-    let hashtable = &mut *htab;
-    let key = CStr::from_ptr(key);
+    if htab.is_null() || key.is_null() {
+        return -1;
+    }
+
+    crate::catch_panic(-1, || {
+        let hashtable = &*htab;
+
+        CStr::from_ptr(key)
+            .to_str()
+            .ok()
+            .and_then(|key| hashtable.get_int(key))
+            .unwrap_or(-1)
+    })
+}
 
-    match hashtable.0.get(key) {
-        Some(item) => item.value,
-        None => -1,
+/// Look up the integer associated with `key`, writing it to `*value`.
+///
+/// Returns `0` if the integer was found, or `-1` if there is no such entry,
+/// the entry doesn't hold an integer, the key isn't valid UTF-8, or any
+/// pointer is null. `*value` is only written to on success.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_htab_find_checked(
+    htab: *mut HashTable,
+    key: *const c_char,
+    value: *mut c_int,
+) -> c_int {
+    if htab.is_null() || key.is_null() || value.is_null() {
+        return -1;
     }
+
+    crate::catch_panic(-1, || {
+        let hashtable = &*htab;
+        let found = CStr::from_ptr(key)
+            .to_str()
+            .ok()
+            .and_then(|key| hashtable.get_int(key));
+
+        match found {
+            Some(found) => {
+                *value = found;
+                0
+            },
+            None => -1,
+        }
+    })
 }
 
+/// Look up the NUL-terminated opaque value associated with `key`.
+///
+/// Returns a null pointer if there is no such entry, the entry doesn't hold
+/// an opaque value, or either pointer is null. The value is owned by the
+/// table and is invalidated when the entry is modified.
 #[no_mangle]
 pub unsafe extern "C" fn tvm_htab_find_ref(
     htab: *mut HashTable,
     key: *const c_char,
 ) -> *mut c_char {
-    let hashtable = &mut *htab;
-    let key = CStr::from_ptr(key);
+    tvm_htab_find_ref_len(htab, key, ptr::null_mut())
+}
+
+/// Like [`tvm_htab_find_ref()`], but also writes the value's length (not
+/// including the NUL terminator) to `length` so values containing NUL bytes
+/// can be read in full.
+///
+/// Passing a null `length` is allowed. It isn't written to when the key
+/// can't be found.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_htab_find_ref_len(
+    htab: *mut HashTable,
+    key: *const c_char,
+    length: *mut c_int,
+) -> *mut c_char {
+    if htab.is_null() || key.is_null() {
+        return ptr::null_mut();
+    }
+
+    crate::catch_panic(ptr::null_mut(), || {
+        let hashtable = &*htab;
+        let item = CStr::from_ptr(key)
+            .to_str()
+            .ok()
+            .and_then(|key| hashtable.get(key));
+
+        match item {
+            Some(Item::Bytes(bytes)) | Some(Item::Both { bytes, .. }) => {
+                if !length.is_null() {
+                    *length = bytes.len() as c_int;
+                }
+                bytes.as_ptr() as *mut c_char
+            },
+            // entries without an opaque value are treated as missing
+            _ => ptr::null_mut(),
+        }
+    })
+}
+
+/// Get the number of entries in a [`HashTable`], or `-1` if `htab` is null.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_htab_len(htab: *const HashTable) -> c_int {
+    if htab.is_null() {
+        return -1;
+    }
+
+    crate::catch_panic(-1, || (*htab).len() as c_int)
+}
+
+/// A callback invoked by [`tvm_htab_foreach()`] for each entry in a
+/// [`HashTable`].
+///
+/// `value` points to the entry's integer and `valptr` to its NUL-terminated
+/// opaque value (`length` bytes long, not including the terminator). Either
+/// may be null if the entry doesn't hold that kind of value. None of the
+/// pointers are valid after the callback returns.
+///
+/// Returning a non-zero value stops the iteration early.
+pub type Visitor = Option<
+    unsafe extern "C" fn(
+        key: *const c_char,
+        value: *const c_int,
+        valptr: *const c_char,
+        length: c_int,
+        user_data: *mut c_void,
+    ) -> c_int,
+>;
+
+/// Call `visitor` for each entry in a [`HashTable`], sorted by key.
+///
+/// Keys containing a NUL byte can't be passed to C, so entries with those
+/// keys (which can only be added using the Rust API) are skipped.
+///
+/// Returns `0` once every entry has been visited, the visitor's return value
+/// if it stopped the iteration early, or `-1` if `htab` or `visitor` are null.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_htab_foreach(
+    htab: *const HashTable,
+    visitor: Visitor,
+    user_data: *mut c_void,
+) -> c_int {
+    let visitor = match visitor {
+        Some(v) if !htab.is_null() => v,
+        _ => return -1,
+    };
+
+    crate::catch_panic(-1, || {
+        for (key, item) in (*htab).iter() {
+            // keys added from C can't contain NUL bytes, but ones added from
+            // Rust might, and there's no way to pass those to the visitor
+            let key = match CString::new(key) {
+                Ok(k) => k,
+                Err(_) => continue,
+            };
+            let (value, bytes) = match item {
+                Item::Int(int) => (int as *const c_int, None),
+                Item::Bytes(bytes) => (ptr::null(), Some(bytes)),
+                Item::Both { int, bytes } => (int as *const c_int, Some(bytes)),
+            };
+            let (valptr, length) = match bytes {
+                Some(bytes) => (bytes.as_ptr(), bytes.len() as c_int),
+                None => (ptr::null(), 0),
+            };
+
+            let ret = visitor(key.as_ptr(), value, valptr, length, user_data);
+
+            if ret != 0 {
+                return ret;
+            }
+        }
+
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_retrieve() {
+        let mut table = HashTable::new();
+
+        table.insert_int("start", 3);
+        table.insert_str("FOO", "bar");
+
+        assert_eq!(table.len(), 2);
+        assert!(table.contains("start"));
+        assert_eq!(table.get_int("start"), Some(3));
+        assert_eq!(table.get_str("FOO"), Some("bar"));
+        assert_eq!(table.get_int("missing"), None);
+    }
+
+    #[test]
+    fn remove_an_entry() {
+        let mut table = HashTable::new();
+        table.insert_int("start", 3);
+
+        let got = table.remove("start").unwrap();
+
+        assert_eq!(got, Item::Int(3));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn entries_added_from_c_are_visible() {
+        let mut table = HashTable::new();
+
+        unsafe {
+            let ret = tvm_htab_add(&mut table, b"loop\0".as_ptr().cast(), 7);
+            assert_eq!(ret, 0);
+        }
+
+        let entries: Vec<_> = table
+            .iter()
+            .map(|(key, item)| (key, item.as_int().unwrap()))
+            .collect();
+        assert_eq!(entries, vec![("loop", 7)]);
+    }
+
+    #[test]
+    fn typed_accessors_report_mismatches() {
+        let mut table = HashTable::new();
+        table.insert_str("FOO", "bar");
+
+        let err = table.get("FOO").unwrap().as_int().unwrap_err();
+
+        assert_eq!(
+            err,
+            TypeMismatch {
+                expected: ValueKind::Int,
+                found: ValueKind::Bytes
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Expected an integer but found opaque bytes"
+        );
+        assert_eq!(table.get_int("FOO"), None);
+    }
+
+    #[test]
+    fn find_on_a_define_isnt_an_integer() {
+        let mut table = HashTable::new();
+        table.insert_str("FOO", "0");
+
+        let got =
+            unsafe { tvm_htab_find(&mut table, b"FOO\0".as_ptr().cast()) };
+
+        assert_eq!(got, -1);
+    }
+
+    #[test]
+    fn checked_find_distinguishes_missing_entries() {
+        let mut table = HashTable::new();
+        table.insert_int("minus_one", -1);
+        let mut value = 0;
+
+        unsafe {
+            let found = tvm_htab_find_checked(
+                &mut table,
+                b"minus_one ".as_ptr().cast(),
+                &mut value,
+            );
+            assert_eq!((found, value), (0, -1));
+
+            value = 42;
+            let missing = tvm_htab_find_checked(
+                &mut table,
+                b"missing ".as_ptr().cast(),
+                &mut value,
+            );
+            assert_eq!((missing, value), (-1, 42));
+        }
+    }
+
+    #[test]
+    fn adding_both_kinds_of_value_keeps_both() {
+        let mut table = HashTable::new();
+        let key = b"key\0".as_ptr().cast();
+        let mut value = *b"value";
+
+        unsafe {
+            tvm_htab_add(&mut table, key, 42);
+            tvm_htab_add_ref(&mut table, key, value.as_mut_ptr().cast(), 5);
+        }
+
+        let item = table.get("key").unwrap();
+        assert_eq!(item.as_int(), Ok(42));
+        assert_eq!(item.as_bytes(), Ok(&b"value"[..]));
+    }
+
+    #[test]
+    fn opaque_values_are_null_terminated() {
+        let mut table = HashTable::new();
+        table.insert_str("FOO", "bar");
+
+        let got = unsafe {
+            let value = tvm_htab_find_ref(&mut table, b"FOO\0".as_ptr().cast());
+            CStr::from_ptr(value)
+        };
+
+        assert_eq!(got.to_bytes(), b"bar");
+    }
+
+    #[test]
+    fn look_up_binary_values_with_their_length() {
+        let mut table = HashTable::new();
+        let key = b"key\0".as_ptr().cast();
+        let mut value = *b"a\0b";
+        let mut length = 0;
+
+        let got = unsafe {
+            tvm_htab_add_ref(&mut table, key, value.as_mut_ptr().cast(), 3);
+            let ptr = tvm_htab_find_ref_len(&mut table, key, &mut length);
+            std::slice::from_raw_parts(ptr as *const u8, length as usize + 1)
+        };
+
+        assert_eq!(length, 3);
+        assert_eq!(got, b"a\0b\0");
+    }
+
+    #[test]
+    fn null_pointers_are_rejected() {
+        let mut table = HashTable::new();
+        let key = b"key\0".as_ptr().cast();
+        let null_table: *mut HashTable = ptr::null_mut();
+
+        unsafe {
+            assert_eq!(tvm_htab_add(null_table, key, 1), -1);
+            assert_eq!(tvm_htab_add(&mut table, ptr::null(), 1), -1);
+            assert_eq!(
+                tvm_htab_add_ref(null_table, key, ptr::null_mut(), 0),
+                -1
+            );
+            assert_eq!(
+                tvm_htab_add_ref(&mut table, ptr::null(), ptr::null_mut(), 0),
+                -1
+            );
+            assert_eq!(tvm_htab_find(null_table, key), -1);
+            assert_eq!(tvm_htab_find(&mut table, ptr::null()), -1);
+            assert!(tvm_htab_find_ref(null_table, key).is_null());
+            assert!(tvm_htab_find_ref(&mut table, ptr::null()).is_null());
+            tvm_htab_destroy(null_table);
+        }
+
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn negative_lengths_are_rejected() {
+        let mut table = HashTable::new();
+        let mut value = *b"value";
+
+        let ret = unsafe {
+            tvm_htab_add_ref(
+                &mut table,
+                b"key\0".as_ptr().cast(),
+                value.as_mut_ptr().cast(),
+                -5,
+            )
+        };
+
+        assert_eq!(ret, -1);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn iteration_is_sorted_by_key() {
+        let mut table = HashTable::new();
+        table.insert_int("loop", 4);
+        table.insert_int("end", 9);
+        table.insert_int("start", 0);
+
+        let keys: Vec<_> = table.iter().map(|(key, _)| key).collect();
+
+        assert_eq!(keys, vec!["end", "loop", "start"]);
+    }
+
+    unsafe extern "C" fn record_entry(
+        key: *const c_char,
+        value: *const c_int,
+        valptr: *const c_char,
+        length: c_int,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let seen = &mut *(user_data as *mut Vec<(String, c_int, String)>);
+        let key = CStr::from_ptr(key).to_str().unwrap().to_string();
+        let value = if value.is_null() { -1 } else { *value };
+        let bytes = if valptr.is_null() {
+            String::new()
+        } else {
+            let bytes = std::slice::from_raw_parts(
+                valptr as *const u8,
+                length as usize,
+            );
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+
+        seen.push((key, value, bytes));
+
+        if seen.len() == 2 {
+            42
+        } else {
+            0
+        }
+    }
+
+    #[test]
+    fn visit_entries_from_c() {
+        let mut table = HashTable::new();
+        table.insert_int("a", 1);
+        table.insert_str("b", "two");
+        table.insert_int("c", 3);
+        let mut seen: Vec<(String, c_int, String)> = Vec::new();
+
+        let ret = unsafe {
+            assert_eq!(tvm_htab_len(&table), 3);
+            tvm_htab_foreach(
+                &table,
+                Some(record_entry),
+                &mut seen as *mut _ as *mut c_void,
+            )
+        };
+
+        // the visitor asked us to stop after the second entry
+        assert_eq!(ret, 42);
+        assert_eq!(
+            seen,
+            vec![
+                (String::from("a"), 1, String::new()),
+                (String::from("b"), -1, String::from("two")),
+            ]
+        );
+    }
+
+    #[test]
+    fn iterating_with_null_pointers() {
+        let table = HashTable::new();
+
+        unsafe {
+            assert_eq!(tvm_htab_len(ptr::null()), -1);
+            assert_eq!(
+                tvm_htab_foreach(
+                    ptr::null(),
+                    Some(record_entry),
+                    ptr::null_mut()
+                ),
+                -1
+            );
+            assert_eq!(tvm_htab_foreach(&table, None, ptr::null_mut()), -1);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trip_through_json() {
+        let mut table = HashTable::new();
+        table.insert_int("start", 0);
+        table.insert_str("FOO", "bar");
+        table
+            .0
+            .insert(String::from("binary"), Item::opaque(vec![0xff, 0x00]));
+        unsafe {
+            tvm_htab_add(&mut table, b"FOO\0".as_ptr().cast(), 7);
+        }
+
+        let json = serde_json::to_string(&table).unwrap();
+        let got: HashTable = serde_json::from_str(&json).unwrap();
 
-    match hashtable.0.get(key) {
-        Some(item) => item.opaque_value.as_ptr() as *mut c_char,
-        None => ptr::null_mut(),
+        assert_eq!(
+            json,
+            r#"{"FOO":{"both":{"int":7,"bytes":"bar"}},"binary":{"bytes":[255,0]},"start":{"int":0}}"#
+        );
+        assert_eq!(got, table);
     }
 }
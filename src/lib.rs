@@ -2,11 +2,66 @@
 //!
 //! [tinyvm]: https://github.com/jakogut/tinyvm
 
+mod breakpoints;
+mod cancel;
+mod coverage;
+mod host;
 mod htab;
+mod memory;
+mod opcode;
+mod parser;
 mod preprocessing;
+mod profile;
+mod program;
+mod register;
+mod snapshot;
+mod source_map;
+mod trace;
+mod vm;
 
-pub use htab::HashTable;
-pub use preprocessing::{preprocess, PreprocessingError};
+pub use breakpoints::Watchpoint;
+pub use cancel::CancellationToken;
+pub use coverage::{Branch, Coverage};
+pub use host::{HostContext, HostError, HostFunction};
+pub use htab::{HashTable, Item, Opaque, TypeMismatch, ValueKind};
+pub use memory::{Memory, MemoryMut, OutOfBounds};
+pub use opcode::Opcode;
+pub use parser::ParseError;
+pub use preprocessing::{
+    preprocess, preprocess_with_builtins, preprocess_with_source_map, Builtins,
+    PreprocessingError,
+};
+pub use profile::{HotSpot, Profile, Region};
+pub use program::{Instruction, Operand, Program};
+pub use register::{Register, UnknownRegister};
+pub use snapshot::{Snapshot, SnapshotError, SNAPSHOT_VERSION};
+pub use source_map::{SourceLocation, SourceMap};
+pub use trace::TraceFormat;
+pub use vm::{
+    BuildError, LoadError, RunError, RunOutcome, StepStatus, Vm, VmBuilder,
+    VmFault,
+};
 
-#[allow(non_camel_case_types, non_snake_case)]
+/// The amount of memory given to a VM by default (`MIN_MEMORY_SIZE` in
+/// `tvm_memory.h`).
+pub const DEFAULT_MEMORY_SIZE: usize = 64 * 1024 * 1024;
+
+/// The amount of memory reserved for the stack (`MIN_STACK_SIZE` in
+/// `tvm_stack.h`).
+pub const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+#[allow(non_camel_case_types)]
 pub mod ffi;
+
+use std::panic::{self, AssertUnwindSafe};
+
+/// Run `func`, returning `on_panic` if it panics.
+///
+/// Unwinding across an `extern "C"` function is undefined behaviour, so the
+/// body of every function we export to C should be wrapped in this.
+pub(crate) fn catch_panic<F, T>(on_panic: T, func: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    panic::catch_unwind(AssertUnwindSafe(func)).unwrap_or(on_panic)
+}
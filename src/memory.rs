@@ -0,0 +1,464 @@
+use std::{
+    error::Error,
+    fmt::{self, Debug, Display, Formatter},
+    mem::size_of,
+    ops::Range,
+    sync::Arc,
+};
+
+/// The number of bytes in each page of memory.
+pub(crate) const PAGE_SIZE: usize = 4096;
+
+pub(crate) type Page = [u8; PAGE_SIZE];
+
+static ZERO_PAGE: Page = [0; PAGE_SIZE];
+
+/// A VM's address space (the equivalent of `tvm_mem`'s `mem_space`).
+///
+/// Memory is split into pages which are shared copy-on-write when the memory
+/// is cloned, so forking a VM doesn't copy anything until one side writes to
+/// it. Pages which have never been written to aren't allocated at all.
+#[derive(Default, Clone)]
+pub(crate) struct PagedMemory {
+    pages: Vec<Option<Arc<Page>>>,
+    len: usize,
+}
+
+impl PagedMemory {
+    /// Create `len` bytes of zeroed memory.
+    pub(crate) fn new(len: usize) -> PagedMemory {
+        let num_pages = len.div_ceil(PAGE_SIZE);
+
+        PagedMemory {
+            pages: vec![None; num_pages],
+            len,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> PagedMemory {
+        let pages = bytes
+            .chunks(PAGE_SIZE)
+            .map(|chunk| {
+                if chunk.iter().all(|&b| b == 0) {
+                    None
+                } else {
+                    let mut page = ZERO_PAGE;
+                    page[..chunk.len()].copy_from_slice(chunk);
+                    Some(Arc::new(page))
+                }
+            })
+            .collect();
+
+        PagedMemory {
+            pages,
+            len: bytes.len(),
+        }
+    }
+
+    /// Create `len` bytes of memory from the `(index, page)` pairs returned
+    /// by [`PagedMemory::allocated()`], or `None` if a page is outside
+    /// memory, appears twice, or has data past the end of memory.
+    pub(crate) fn from_pages<I>(len: usize, pages: I) -> Option<PagedMemory>
+    where
+        I: IntoIterator<Item = (usize, Page)>,
+    {
+        let mut memory = PagedMemory::new(len);
+
+        for (index, page) in pages {
+            let start = index.checked_mul(PAGE_SIZE)?;
+            let end = usize::min(PAGE_SIZE, len.checked_sub(start)?);
+            let slot = memory.pages.get_mut(index)?;
+            if slot.is_some() || page[end..].iter().any(|&b| b != 0) {
+                return None;
+            }
+            *slot = Some(Arc::new(page));
+        }
+
+        Some(memory)
+    }
+
+    pub(crate) fn len(&self) -> usize { self.len }
+
+    #[cfg(test)]
+    pub(crate) fn to_vec(&self) -> Vec<u8> {
+        self.chunks().flatten().copied().collect()
+    }
+
+    /// The contents of memory, one page at a time.
+    #[cfg(test)]
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        (0..self.pages.len()).map(move |i| {
+            let end = usize::min(PAGE_SIZE, self.len - i * PAGE_SIZE);
+            &self.page(i)[..end]
+        })
+    }
+
+    /// The number of pages which have been written to.
+    pub(crate) fn allocated_pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+
+    /// The pages which have been written to, and their index.
+    pub(crate) fn allocated(&self) -> impl Iterator<Item = (usize, &Page)> {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, page)| page.as_deref().map(|page| (i, page)))
+    }
+
+    /// The number of allocated pages which are shared with `other`.
+    #[cfg(test)]
+    pub(crate) fn shared_pages(&self, other: &PagedMemory) -> usize {
+        self.pages
+            .iter()
+            .zip(&other.pages)
+            .filter(|(a, b)| match (a, b) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                _ => false,
+            })
+            .count()
+    }
+
+    fn page(&self, index: usize) -> &Page {
+        match &self.pages[index] {
+            Some(page) => page,
+            None => &ZERO_PAGE,
+        }
+    }
+
+    fn read(
+        &self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), OutOfBounds> {
+        let range = check_bounds(self.len, address, buffer.len())?;
+        let mut copied = 0;
+
+        for (page, offset, length) in pages_in(range) {
+            buffer[copied..copied + length]
+                .copy_from_slice(&self.page(page)[offset..offset + length]);
+            copied += length;
+        }
+
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        address: usize,
+        data: &[u8],
+    ) -> Result<(), OutOfBounds> {
+        let range = check_bounds(self.len, address, data.len())?;
+        let mut copied = 0;
+
+        for (page, offset, length) in pages_in(range) {
+            // copies the page if it's shared with another VM
+            let page = Arc::make_mut(
+                self.pages[page].get_or_insert_with(|| Arc::new(ZERO_PAGE)),
+            );
+            page[offset..offset + length]
+                .copy_from_slice(&data[copied..copied + length]);
+            copied += length;
+        }
+
+        Ok(())
+    }
+}
+
+impl PartialEq for PagedMemory {
+    fn eq(&self, other: &PagedMemory) -> bool {
+        self.len == other.len
+            && (0..self.pages.len()).all(|i| self.page(i) == other.page(i))
+    }
+}
+
+impl Debug for PagedMemory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PagedMemory")
+            .field("len", &self.len)
+            .field("allocated_pages", &self.allocated_pages())
+            .finish()
+    }
+}
+
+/// How [`PagedMemory`] is serialized. Only allocated pages are included,
+/// because memory is usually megabytes of zeroes.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SparseMemory {
+    len: usize,
+    pages: Vec<(usize, Vec<u8>)>,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PagedMemory {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let sparse = SparseMemory {
+            len: self.len,
+            pages: self
+                .allocated()
+                .map(|(index, page)| (index, page.to_vec()))
+                .collect(),
+        };
+
+        serde::Serialize::serialize(&sparse, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PagedMemory {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PagedMemory, D::Error> {
+        use serde::de::Error as _;
+        use std::convert::TryInto;
+
+        let sparse: SparseMemory =
+            serde::Deserialize::deserialize(deserializer)?;
+        let mut pages = Vec::new();
+        for (index, bytes) in sparse.pages {
+            let page: Page = bytes.as_slice().try_into().map_err(|_| {
+                D::Error::invalid_length(bytes.len(), &"a 4096 byte page")
+            })?;
+            pages.push((index, page));
+        }
+
+        PagedMemory::from_pages(sparse.len, pages)
+            .ok_or_else(|| D::Error::custom("Invalid memory pages"))
+    }
+}
+
+/// Split a range of addresses into `(page, offset, length)` chunks which
+/// each fall within a single page.
+fn pages_in(
+    range: Range<usize>,
+) -> impl Iterator<Item = (usize, usize, usize)> {
+    let mut address = range.start;
+
+    std::iter::from_fn(move || {
+        if address >= range.end {
+            return None;
+        }
+
+        let offset = address % PAGE_SIZE;
+        let length = usize::min(PAGE_SIZE - offset, range.end - address);
+        let chunk = (address / PAGE_SIZE, offset, length);
+        address += length;
+
+        Some(chunk)
+    })
+}
+
+/// A read-only view of a VM's memory.
+///
+/// Addresses are byte offsets from the start of memory. Note that in TinyVM
+/// assembly a memory operand like `[n]` refers to the 32-bit integer at byte
+/// address `n * 4`.
+#[derive(Debug, Copy, Clone)]
+pub struct Memory<'vm> {
+    memory: &'vm PagedMemory,
+}
+
+impl<'vm> Memory<'vm> {
+    pub(crate) fn new(memory: &'vm PagedMemory) -> Self { Memory { memory } }
+
+    /// The size of memory, in bytes.
+    pub fn len(&self) -> usize { self.memory.len() }
+
+    pub fn is_empty(&self) -> bool { self.memory.len() == 0 }
+
+    /// Read the (native-endian) 32-bit integer starting at `address`.
+    pub fn read_i32(&self, address: usize) -> Result<i32, OutOfBounds> {
+        read_i32(self.memory, address)
+    }
+
+    /// Fill `buffer` with the bytes starting at `address`.
+    pub fn read_bytes(
+        &self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), OutOfBounds> {
+        self.memory.read(address, buffer)
+    }
+}
+
+/// A mutable view of a VM's memory.
+///
+/// See [`Memory`] for how addresses are interpreted.
+#[derive(Debug)]
+pub struct MemoryMut<'vm> {
+    memory: &'vm mut PagedMemory,
+}
+
+impl<'vm> MemoryMut<'vm> {
+    pub(crate) fn new(memory: &'vm mut PagedMemory) -> Self {
+        MemoryMut { memory }
+    }
+
+    /// The size of memory, in bytes.
+    pub fn len(&self) -> usize { self.memory.len() }
+
+    pub fn is_empty(&self) -> bool { self.memory.len() == 0 }
+
+    /// Read the (native-endian) 32-bit integer starting at `address`.
+    pub fn read_i32(&self, address: usize) -> Result<i32, OutOfBounds> {
+        read_i32(self.memory, address)
+    }
+
+    /// Fill `buffer` with the bytes starting at `address`.
+    pub fn read_bytes(
+        &self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), OutOfBounds> {
+        self.memory.read(address, buffer)
+    }
+
+    /// Write a (native-endian) 32-bit integer to `address`.
+    pub fn write_i32(
+        &mut self,
+        address: usize,
+        value: i32,
+    ) -> Result<(), OutOfBounds> {
+        self.write_bytes(address, &value.to_ne_bytes())
+    }
+
+    /// Copy `data` into memory, starting at `address`.
+    pub fn write_bytes(
+        &mut self,
+        address: usize,
+        data: &[u8],
+    ) -> Result<(), OutOfBounds> {
+        self.memory.write(address, data)
+    }
+
+    /// Reborrow as a read-only view.
+    pub fn as_memory(&self) -> Memory<'_> { Memory::new(self.memory) }
+}
+
+fn read_i32(memory: &PagedMemory, address: usize) -> Result<i32, OutOfBounds> {
+    let mut buffer = [0; size_of::<i32>()];
+    memory.read(address, &mut buffer)?;
+
+    Ok(i32::from_ne_bytes(buffer))
+}
+
+fn check_bounds(
+    memory_size: usize,
+    address: usize,
+    length: usize,
+) -> Result<Range<usize>, OutOfBounds> {
+    match address.checked_add(length) {
+        Some(end) if end <= memory_size => Ok(address..end),
+        _ => Err(OutOfBounds {
+            address,
+            length,
+            memory_size,
+        }),
+    }
+}
+
+/// The error returned when trying to access memory outside of the VM's
+/// address space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutOfBounds {
+    /// The address being accessed.
+    pub address: usize,
+    /// The number of bytes being accessed.
+    pub length: usize,
+    /// The size of the VM's memory.
+    pub memory_size: usize,
+}
+
+impl Display for OutOfBounds {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unable to access {} bytes at address {:#x} (memory size: {} bytes)",
+            self.length, self.address, self.memory_size
+        )
+    }
+}
+
+impl Error for OutOfBounds {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_then_read_an_integer() {
+        let mut buffer = PagedMemory::new(16);
+        let mut memory = MemoryMut::new(&mut buffer);
+
+        memory.write_i32(4, -42).unwrap();
+
+        assert_eq!(memory.read_i32(4).unwrap(), -42);
+        assert_eq!(memory.as_memory().read_i32(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_and_write_bytes() {
+        let mut buffer = PagedMemory::new(8);
+        let mut memory = MemoryMut::new(&mut buffer);
+        let mut got = [0; 3];
+
+        memory.write_bytes(5, b"abc").unwrap();
+        memory.read_bytes(5, &mut got).unwrap();
+
+        assert_eq!(&got, b"abc");
+    }
+
+    #[test]
+    fn out_of_range_accesses_are_errors() {
+        let mut buffer = PagedMemory::new(8);
+        let mut memory = MemoryMut::new(&mut buffer);
+
+        assert_eq!(
+            memory.write_i32(6, 1).unwrap_err(),
+            OutOfBounds {
+                address: 6,
+                length: 4,
+                memory_size: 8
+            }
+        );
+        assert!(memory.read_i32(8).is_err());
+        assert!(memory.read_bytes(usize::MAX, &mut [0; 2]).is_err());
+        // zero-length accesses at the very end are fine
+        assert!(memory.write_bytes(8, &[]).is_ok());
+    }
+
+    #[test]
+    fn accesses_can_span_pages() {
+        let mut memory = PagedMemory::new(3 * PAGE_SIZE);
+        let data: Vec<u8> = (1..=10).collect();
+
+        memory.write(PAGE_SIZE - 3, &data).unwrap();
+
+        let mut got = [0; 10];
+        memory.read(PAGE_SIZE - 3, &mut got).unwrap();
+        assert_eq!(&got[..], &data[..]);
+        assert_eq!(memory.allocated_pages(), 2);
+        assert_eq!(PagedMemory::from_bytes(&memory.to_vec()), memory);
+    }
+
+    #[test]
+    fn clones_share_pages_until_written() {
+        let mut original = PagedMemory::new(2 * PAGE_SIZE);
+        original.write(0, &[1]).unwrap();
+        original.write(PAGE_SIZE, &[2]).unwrap();
+
+        let mut copy = original.clone();
+        assert_eq!(copy.shared_pages(&original), 2);
+
+        copy.write(1, &[3]).unwrap();
+
+        assert_eq!(copy.shared_pages(&original), 1);
+        assert_eq!(read_i32(&original, 0).unwrap().to_ne_bytes()[1], 0);
+        assert_eq!(read_i32(&copy, 0).unwrap().to_ne_bytes()[1], 3);
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt::{self, Display, Formatter};
+
+/// The instructions understood by the VM.
+///
+/// Discriminants match the indices into `tvm_opcode_map` in `tvm_parser.c`,
+/// with our own extensions (e.g. `inp`) added to the end.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Opcode {
+    Nop = 0x0,
+    Int = 0x1,
+    Mov = 0x2,
+    Push = 0x3,
+    Pop = 0x4,
+    Pushf = 0x5,
+    Popf = 0x6,
+    Inc = 0x7,
+    Dec = 0x8,
+    Add = 0x9,
+    Sub = 0xA,
+    Mul = 0xB,
+    Div = 0xC,
+    Mod = 0xD,
+    Rem = 0xE,
+    Not = 0xF,
+    Xor = 0x10,
+    Or = 0x11,
+    And = 0x12,
+    Shl = 0x13,
+    Shr = 0x14,
+    Cmp = 0x15,
+    Jmp = 0x16,
+    Call = 0x17,
+    Ret = 0x18,
+    Je = 0x19,
+    Jne = 0x1A,
+    Jg = 0x1B,
+    Jge = 0x1C,
+    Jl = 0x1D,
+    Jle = 0x1E,
+    Prn = 0x1F,
+    /// Read a single byte from the VM's input, or `-1` at end-of-file.
+    Inp = 0x20,
+}
+
+impl Opcode {
+    /// Every opcode, in the same order as `tvm_opcode_map`.
+    pub const ALL: [Opcode; 33] = [
+        Opcode::Nop,
+        Opcode::Int,
+        Opcode::Mov,
+        Opcode::Push,
+        Opcode::Pop,
+        Opcode::Pushf,
+        Opcode::Popf,
+        Opcode::Inc,
+        Opcode::Dec,
+        Opcode::Add,
+        Opcode::Sub,
+        Opcode::Mul,
+        Opcode::Div,
+        Opcode::Mod,
+        Opcode::Rem,
+        Opcode::Not,
+        Opcode::Xor,
+        Opcode::Or,
+        Opcode::And,
+        Opcode::Shl,
+        Opcode::Shr,
+        Opcode::Cmp,
+        Opcode::Jmp,
+        Opcode::Call,
+        Opcode::Ret,
+        Opcode::Je,
+        Opcode::Jne,
+        Opcode::Jg,
+        Opcode::Jge,
+        Opcode::Jl,
+        Opcode::Jle,
+        Opcode::Prn,
+        Opcode::Inp,
+    ];
+
+    /// Look up the opcode with a particular numeric value.
+    pub fn from_i32(value: i32) -> Option<Opcode> {
+        if value < 0 {
+            return None;
+        }
+
+        Opcode::ALL.get(value as usize).copied()
+    }
+
+    /// Look up an opcode by its [`Opcode::mnemonic()`].
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        Opcode::ALL
+            .iter()
+            .copied()
+            .find(|op| op.mnemonic() == mnemonic)
+    }
+
+    /// The number of operands this instruction takes.
+    pub fn operand_count(self) -> usize {
+        match self {
+            Opcode::Nop | Opcode::Pushf | Opcode::Popf | Opcode::Ret => 0,
+            Opcode::Mov
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Mod
+            | Opcode::Xor
+            | Opcode::Or
+            | Opcode::And
+            | Opcode::Shl
+            | Opcode::Shr
+            | Opcode::Cmp => 2,
+            _ => 1,
+        }
+    }
+
+    /// The name used for this instruction in assembly.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Opcode::Nop => "nop",
+            Opcode::Int => "int",
+            Opcode::Mov => "mov",
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::Pushf => "pushf",
+            Opcode::Popf => "popf",
+            Opcode::Inc => "inc",
+            Opcode::Dec => "dec",
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Mul => "mul",
+            Opcode::Div => "div",
+            Opcode::Mod => "mod",
+            Opcode::Rem => "rem",
+            Opcode::Not => "not",
+            Opcode::Xor => "xor",
+            Opcode::Or => "or",
+            Opcode::And => "and",
+            Opcode::Shl => "shl",
+            Opcode::Shr => "shr",
+            Opcode::Cmp => "cmp",
+            Opcode::Jmp => "jmp",
+            Opcode::Call => "call",
+            Opcode::Ret => "ret",
+            Opcode::Je => "je",
+            Opcode::Jne => "jne",
+            Opcode::Jg => "jg",
+            Opcode::Jge => "jge",
+            Opcode::Jl => "jl",
+            Opcode::Jle => "jle",
+            Opcode::Prn => "prn",
+            Opcode::Inp => "inp",
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_are_in_libtvm_order() {
+        for (i, opcode) in Opcode::ALL.iter().enumerate() {
+            assert_eq!(*opcode as usize, i);
+            assert_eq!(Opcode::from_i32(i as i32), Some(*opcode));
+        }
+
+        assert_eq!(Opcode::from_i32(-1), None);
+        assert_eq!(Opcode::from_i32(Opcode::ALL.len() as i32), None);
+    }
+
+    #[test]
+    fn look_up_opcodes_by_mnemonic() {
+        for opcode in Opcode::ALL.iter().copied() {
+            assert_eq!(Opcode::from_mnemonic(opcode.mnemonic()), Some(opcode));
+        }
+
+        assert_eq!(Opcode::from_mnemonic("inp"), Some(Opcode::Inp));
+        assert_eq!(Opcode::from_mnemonic("MOV"), None);
+    }
+}
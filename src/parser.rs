@@ -0,0 +1,414 @@
+//! A port of `tvm_lexer.c` and `tvm_parser.c`.
+
+use crate::{
+    program::{Instruction, Operand, Program},
+    HashTable, Opcode, Register, SourceMap,
+};
+use std::{
+    collections::btree_map::{BTreeMap, Entry},
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+/// Parse preprocessed source code into a [`Program`], substituting any
+/// symbols from `defines`.
+pub(crate) fn parse(
+    src: &str,
+    defines: &HashTable,
+) -> Result<Program, ParseError> {
+    let lines = tokenize(src, defines);
+    let labels = find_labels(&lines)?;
+
+    let mut instructions = Vec::new();
+
+    for line in &lines {
+        let tokens = line.instruction_tokens();
+        let (mnemonic, operands) = match tokens.split_first() {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        instructions.push(parse_instruction(
+            line.number,
+            mnemonic,
+            operands,
+            &labels,
+        )?);
+    }
+
+    Ok(Program {
+        instructions,
+        start: labels.get("start").copied().unwrap_or(0),
+        labels,
+        source_map: SourceMap::default(),
+    })
+}
+
+/// The tokens from a single non-empty line of source code.
+#[derive(Debug)]
+struct Line {
+    number: usize,
+    tokens: Vec<String>,
+}
+
+impl Line {
+    fn labels(&self) -> impl Iterator<Item = &str> + '_ {
+        self.tokens
+            .iter()
+            .take_while(|tok| is_label(tok))
+            .map(|tok| tok.trim_end_matches(':'))
+    }
+
+    fn instruction_tokens(&self) -> &[String] {
+        let num_labels = self.labels().count();
+        &self.tokens[num_labels..]
+    }
+}
+
+fn is_label(token: &str) -> bool { token.len() > 1 && token.ends_with(':') }
+
+/// Split the source into tokens, skipping comments and empty lines.
+fn tokenize(src: &str, defines: &HashTable) -> Vec<Line> {
+    const DELIMITERS: &[char] = &[' ', '\t', ',', '\r'];
+
+    let mut lines = Vec::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+
+        let tokens: Vec<String> = code
+            .split(DELIMITERS)
+            .filter(|tok| !tok.is_empty())
+            .map(|tok| match defines.get_str(tok) {
+                Some(value) => value.to_string(),
+                None => tok.to_string(),
+            })
+            .collect();
+
+        if !tokens.is_empty() {
+            lines.push(Line {
+                number: i + 1,
+                tokens,
+            });
+        }
+    }
+
+    lines
+}
+
+/// Find the index of the instruction each label refers to.
+fn find_labels(lines: &[Line]) -> Result<BTreeMap<String, usize>, ParseError> {
+    let mut labels = BTreeMap::new();
+    let mut instruction_index = 0;
+
+    for line in lines {
+        for label in line.labels() {
+            match labels.entry(label.to_string()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(instruction_index);
+                },
+                Entry::Occupied(_) => {
+                    return Err(ParseError::DuplicateLabel {
+                        line: line.number,
+                        name: label.to_string(),
+                    });
+                },
+            }
+        }
+
+        if !line.instruction_tokens().is_empty() {
+            instruction_index += 1;
+        }
+    }
+
+    Ok(labels)
+}
+
+fn parse_instruction(
+    line: usize,
+    mnemonic: &str,
+    operands: &[String],
+    labels: &BTreeMap<String, usize>,
+) -> Result<Instruction, ParseError> {
+    let opcode = Opcode::from_mnemonic(mnemonic).ok_or_else(|| {
+        ParseError::UnknownInstruction {
+            line,
+            name: mnemonic.to_string(),
+        }
+    })?;
+
+    if operands.len() != opcode.operand_count() {
+        return Err(ParseError::WrongNumberOfOperands {
+            line,
+            opcode,
+            expected: opcode.operand_count(),
+            found: operands.len(),
+        });
+    }
+
+    let operands = operands
+        .iter()
+        .map(|op| {
+            parse_operand(op, labels).ok_or_else(|| {
+                ParseError::InvalidOperand {
+                    line,
+                    operand: op.clone(),
+                }
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Instruction {
+        opcode,
+        operands,
+        line,
+    })
+}
+
+fn parse_operand(
+    token: &str,
+    labels: &BTreeMap<String, usize>,
+) -> Option<Operand> {
+    if let Ok(register) = token.parse::<Register>() {
+        return Some(Operand::Register(register));
+    }
+
+    if token.starts_with('[') && token.ends_with(']') {
+        let address = parse_value(&token[1..token.len() - 1])?;
+        return Some(Operand::Memory(address as u32));
+    }
+
+    if let Some(&index) = labels.get(token) {
+        return Some(Operand::Value(index as i32));
+    }
+
+    parse_value(token).map(Operand::Value)
+}
+
+/// Parse an integer, which may be written in hex (`0x1F` or `1Fh`), binary
+/// (`0b101`), or decimal.
+///
+/// Like `strtoul()` in the original, values which only fit in an unsigned
+/// 32-bit integer wrap around.
+fn parse_value(token: &str) -> Option<i32> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+
+    let (digits, radix) = if let Some(hex) = digits.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        (binary, 2)
+    } else if let Some(hex) = digits.strip_suffix('h') {
+        (hex, 16)
+    } else {
+        (digits, 10)
+    };
+
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return None;
+    }
+
+    let value = u32::from_str_radix(digits, radix).ok()? as i32;
+
+    if negative {
+        Some(value.wrapping_neg())
+    } else {
+        Some(value)
+    }
+}
+
+/// The reasons parsing a program may fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    UnknownInstruction {
+        line: usize,
+        name: String,
+    },
+    WrongNumberOfOperands {
+        line: usize,
+        opcode: Opcode,
+        expected: usize,
+        found: usize,
+    },
+    /// The operand isn't a register, memory address, label, or number.
+    InvalidOperand {
+        line: usize,
+        operand: String,
+    },
+    DuplicateLabel {
+        line: usize,
+        name: String,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownInstruction { line, name } => {
+                write!(f, "Line {}: unknown instruction, \"{}\"", line, name)
+            },
+            ParseError::WrongNumberOfOperands {
+                line,
+                opcode,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Line {}: {} expects {} operands but found {}",
+                line, opcode, expected, found
+            ),
+            ParseError::InvalidOperand { line, operand } => {
+                write!(f, "Line {}: invalid operand, \"{}\"", line, operand)
+            },
+            ParseError::DuplicateLabel { line, name } => {
+                write!(
+                    f,
+                    "Line {}: the label \"{}\" is already defined",
+                    line, name
+                )
+            },
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(src: &str) -> Result<Program, ParseError> {
+        super::parse(src, &HashTable::new())
+    }
+
+    #[test]
+    fn parse_values() {
+        let inputs = vec![
+            ("42", 42),
+            ("-7", -7),
+            ("0x1F", 0x1F),
+            ("1Fh", 0x1F),
+            ("0b101", 0b101),
+            ("0xFFFFFFFF", -1),
+        ];
+
+        for (src, should_be) in inputs {
+            assert_eq!(parse_value(src), Some(should_be), "{}", src);
+        }
+
+        for src in &["", "0x", "abc", "--1", "4294967296"] {
+            assert_eq!(parse_value(src), None, "{}", src);
+        }
+    }
+
+    #[test]
+    fn parse_a_simple_program() {
+        let src = "
+# comments and blank lines are ignored
+start:
+    mov eax, [3]   # trailing comment
+loop: inc eax
+    jmp loop
+";
+
+        let got = parse(src).unwrap();
+
+        assert_eq!(got.start, 0);
+        assert_eq!(got.labels["loop"], 1);
+        assert_eq!(
+            got.instructions,
+            vec![
+                Instruction {
+                    opcode: Opcode::Mov,
+                    operands: vec![
+                        Operand::Register(Register::Eax),
+                        Operand::Memory(3),
+                    ],
+                    line: 4,
+                },
+                Instruction {
+                    opcode: Opcode::Inc,
+                    operands: vec![Operand::Register(Register::Eax)],
+                    line: 5,
+                },
+                Instruction {
+                    opcode: Opcode::Jmp,
+                    operands: vec![Operand::Value(1)],
+                    line: 6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn start_label_sets_the_entry_point() {
+        let got = parse("nop\nnop\nstart:\nprn 1").unwrap();
+
+        assert_eq!(got.start, 2);
+    }
+
+    #[test]
+    fn substitute_defines() {
+        let mut defines = HashTable::new();
+        defines.insert_str("ANSWER", "42");
+
+        let got = super::parse("prn ANSWER", &defines).unwrap();
+
+        assert_eq!(got.instructions[0].operands, vec![Operand::Value(42)]);
+    }
+
+    #[test]
+    fn detect_errors() {
+        let inputs = vec![
+            (
+                "nop\nfoo eax",
+                ParseError::UnknownInstruction {
+                    line: 2,
+                    name: String::from("foo"),
+                },
+            ),
+            (
+                "mov eax",
+                ParseError::WrongNumberOfOperands {
+                    line: 1,
+                    opcode: Opcode::Mov,
+                    expected: 2,
+                    found: 1,
+                },
+            ),
+            (
+                "prn missing_label",
+                ParseError::InvalidOperand {
+                    line: 1,
+                    operand: String::from("missing_label"),
+                },
+            ),
+            (
+                "a:\nnop\na:\nnop",
+                ParseError::DuplicateLabel {
+                    line: 3,
+                    name: String::from("a"),
+                },
+            ),
+        ];
+
+        for (src, should_be) in inputs {
+            assert_eq!(parse(src).unwrap_err(), should_be);
+        }
+    }
+
+    #[test]
+    fn errors_mention_the_line() {
+        let err = parse("nop\nmov eax").unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Line 2: mov expects 2 operands but found 1"
+        );
+    }
+}
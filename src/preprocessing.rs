@@ -1,81 +1,225 @@
 use crate::{
     ffi::tvm_htab_ctx,
     htab::{HashTable, Item},
+    source_map::SourceMap,
 };
 use std::{
-    collections::hash_map::Entry,
+    collections::btree_map::Entry,
+    error::Error,
     ffi::{CStr, CString},
+    fmt::{self, Display, Formatter},
     io::Error as IoError,
     os::raw::{c_char, c_int},
 };
 
+/// Preprocess the NUL-terminated source text pointed to by `*src`, adding any
+/// `%define`s to `defines`.
+///
+/// On success `*src` is replaced with a newly allocated copy of the
+/// preprocessed text (which the caller must `free()`), `*src_len` is set to
+/// its length, and `0` is returned. If any pointer is null, the source isn't
+/// valid UTF-8, or preprocessing fails, `-1` is returned and the output
+/// arguments are left untouched.
 #[no_mangle]
 pub unsafe extern "C" fn tvm_preprocess(
     src: *mut *mut c_char,
     src_len: *mut c_int,
     defines: *mut tvm_htab_ctx,
 ) -> c_int {
-    if src.is_null() || src_len.is_null() || defines.is_null() {
+    if src.is_null()
+        || (*src).is_null()
+        || src_len.is_null()
+        || defines.is_null()
+    {
         return -1;
     }
 
-    // Safety: This assumes the tvm_htab_ctx is actually our ported HashTable
-    let defines = &mut *(defines as *mut HashTable);
-
-    // convert the input string to an owned Rust string so it can be
-    // preprocessed
-    let rust_src = match CStr::from_ptr(*src).to_str() {
-        Ok(s) => s.to_string(),
-        // just error out if it's not valid UTF-8
-        Err(_) => return -1,
-    };
+    crate::catch_panic(-1, || {
+        // Safety: This assumes the tvm_htab_ctx is actually our ported
+        // HashTable
+        let defines = &mut *(defines as *mut HashTable);
+
+        // convert the input string to an owned Rust string so it can be
+        // preprocessed
+        let rust_src = match CStr::from_ptr(*src).to_str() {
+            Ok(s) => s.to_string(),
+            // just error out if it's not valid UTF-8
+            Err(_) => return -1,
+        };
+
+        let preprocessed = match preprocess(rust_src, defines)
+            .ok()
+            .and_then(|s| CString::new(s).ok())
+        {
+            Some(s) => s,
+            // tell the caller "an error occurred"
+            None => return -1,
+        };
+
+        // create a copy of the preprocessed string that can be free'd by C
+        // and use the output arguments to pass it to the caller
+        let copy = libc::strdup(preprocessed.as_ptr());
+        if copy.is_null() {
+            return -1;
+        }
+        *src = copy;
+        // the original C implementation didn't add a null terminator to the
+        // preprocessed string, so we're required to set the length as well.
+        *src_len = libc::strlen(*src) as c_int;
 
-    match preprocess(rust_src, defines) {
-        Ok(s) => {
-            let preprocessed = CString::new(s).unwrap();
-            // create a copy of the preprocessed string that can be free'd by C
-            // and use the output arguments to pass it to the caller
-            *src = libc::strdup(preprocessed.as_ptr());
-            // the original C implementation didn't add a null terminator to the
-            // preprocessed string, so we're required to set the length as well.
-            *src_len = libc::strlen(*src) as c_int;
-
-            // returning 0 indicates success
-            0
-        },
-        // tell the caller "an error occurred"
-        Err(_) => -1,
-    }
+        // returning 0 indicates success
+        0
+    })
 }
 
+/// Run the preprocessor over some source text, expanding any `%include` and
+/// `%define` directives.
+///
+/// The built-in symbols from [`Builtins::default()`] are defined before
+/// processing starts.
 pub fn preprocess(
     src: String,
     defines: &mut HashTable,
 ) -> Result<String, PreprocessingError> {
-    let mut src = src;
+    preprocess_with_builtins(src, defines, &Builtins::default())
+}
+
+/// Run the preprocessor over some source text, seeding `defines` with the
+/// provided built-in symbols first.
+pub fn preprocess_with_builtins(
+    src: String,
+    defines: &mut HashTable,
+    builtins: &Builtins,
+) -> Result<String, PreprocessingError> {
+    preprocess_with_source_map(src, defines, builtins).map(|(src, _)| src)
+}
+
+/// Run the preprocessor, also returning a [`SourceMap`] which records which
+/// file (i.e. [`Builtins::file`] or an `%include`) and line each line of
+/// output came from.
+pub fn preprocess_with_source_map(
+    src: String,
+    defines: &mut HashTable,
+    builtins: &Builtins,
+) -> Result<(String, SourceMap), PreprocessingError> {
+    builtins.define(defines);
+    let file = builtins.file.as_deref().unwrap_or("<source>");
+    let mut src = expand_builtins(&src, file);
+    let mut source_map = SourceMap::new(file, &src);
 
     loop {
-        let (modified, num_includes) = process_includes(src)?;
+        let (modified, num_includes) = process_includes(src, &mut source_map)?;
         let (modified, num_defines) = process_defines(modified, defines)?;
 
         if num_includes + num_defines == 0 {
-            return Ok(modified);
+            return Ok((modified, source_map));
         }
 
         src = modified;
     }
 }
 
+/// Symbols which are automatically defined before a program is preprocessed.
+///
+/// | Symbol             | Value                                    |
+/// | ------------------ | ---------------------------------------- |
+/// | `__FILE__`         | The file the symbol appears in           |
+/// | `__LINE__`         | The line number the symbol appears on    |
+/// | `__TVM_MEM_SIZE__` | The size of the VM's memory, in bytes    |
+/// | `__TVM_VERSION__`  | The version of this crate                |
+///
+/// Because their values change from file to file and line to line,
+/// `__FILE__` and `__LINE__` are substituted directly into the source text
+/// instead of being added to the define table. Inside an `%include`d file,
+/// `__FILE__` is the name it was included by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Builtins {
+    /// The file being preprocessed, if known. `__FILE__` expands to
+    /// `<source>` when this is `None`.
+    pub file: Option<String>,
+    /// The number of bytes of memory available to the program.
+    pub memory_size: usize,
+}
+
+impl Builtins {
+    /// Add the built-in symbols to a define table, replacing any existing
+    /// entries with the same name.
+    pub fn define(&self, defines: &mut HashTable) {
+        defines.insert_str("__TVM_MEM_SIZE__", &self.memory_size.to_string());
+        defines.insert_str("__TVM_VERSION__", env!("CARGO_PKG_VERSION"));
+    }
+}
+
+impl Default for Builtins {
+    fn default() -> Builtins {
+        Builtins {
+            file: None,
+            memory_size: crate::DEFAULT_MEMORY_SIZE,
+        }
+    }
+}
+
+/// Replace the `__FILE__` and `__LINE__` tokens in a file's text with its
+/// name and the (1-based) number of the line they appear on.
+fn expand_builtins(src: &str, file: &str) -> String {
+    let src = expand_token(src, "__LINE__", |line| line.to_string());
+    expand_token(&src, "__FILE__", |_| file.to_string())
+}
+
+/// Replace every `token` with `value(line)`, where `line` is the (1-based)
+/// number of the line it appears on.
+fn expand_token<F>(src: &str, token: &str, value: F) -> String
+where
+    F: Fn(usize) -> String,
+{
+    if !src.contains(token) {
+        return src.to_string();
+    }
+
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let mut expanded = String::with_capacity(src.len());
+
+    for (i, line) in src.split('\n').enumerate() {
+        if i > 0 {
+            expanded.push('\n');
+        }
+
+        let mut rest = line;
+
+        while let Some(ix) = rest.find(token) {
+            let end = ix + token.len();
+            // only substitute whole tokens, so "FOO__LINE__" is left alone
+            let standalone = !rest[..ix].ends_with(is_ident)
+                && !rest[end..].starts_with(is_ident);
+
+            expanded.push_str(&rest[..ix]);
+            if standalone {
+                expanded.push_str(&value(i + 1));
+            } else {
+                expanded.push_str(token);
+            }
+            rest = &rest[end..];
+        }
+
+        expanded.push_str(rest);
+    }
+
+    expanded
+}
+
 /// Scan through the input string looking for a line starting with some
 /// directive, using a callback to figure out what to replace the directive line
 /// with.
+///
+/// The callback is given the rest of the directive line and its (0-based)
+/// line number.
 fn process_line_starting_with_directive<F>(
     mut src: String,
     directive: &str,
     mut replace_line: F,
 ) -> Result<(String, usize), PreprocessingError>
 where
-    F: FnMut(&str) -> Result<String, PreprocessingError>,
+    F: FnMut(&str, usize) -> Result<String, PreprocessingError>,
 {
     // try to find the first instance of the directive
     let directive_delimiter = match src.find(directive) {
@@ -93,8 +237,10 @@ where
     let directive_line =
         src[directive_delimiter + directive.len()..end_ix].trim();
 
+    let line_number = src[..directive_delimiter].matches('\n').count();
+
     // use the callback to figure out what we should replace the line with
-    let replacement = replace_line(directive_line)?;
+    let replacement = replace_line(directive_line, line_number)?;
 
     // remove the original line
     let _ = src.drain(directive_delimiter..end_ix);
@@ -106,17 +252,27 @@ where
 
 fn process_includes(
     src: String,
+    source_map: &mut SourceMap,
 ) -> Result<(String, usize), PreprocessingError> {
     const TOK_INCLUDE: &str = "%include";
 
-    process_line_starting_with_directive(src, TOK_INCLUDE, |line| {
-        std::fs::read_to_string(line).map_err(|e| {
-            PreprocessingError::FailedInclude {
-                name: line.to_string(),
-                inner: e,
-            }
-        })
-    })
+    process_line_starting_with_directive(
+        src,
+        TOK_INCLUDE,
+        |line, line_number| {
+            let included = std::fs::read_to_string(line)
+                .map(|included| expand_builtins(&included, line))
+                .map_err(|e| PreprocessingError::FailedInclude {
+                    name: line.to_string(),
+                    inner: e,
+                })?;
+
+            let num_lines = included.matches('\n').count() + 1;
+            source_map.include(line_number, line, num_lines);
+
+            Ok(included)
+        },
+    )
 }
 
 fn process_defines(
@@ -125,7 +281,7 @@ fn process_defines(
 ) -> Result<(String, usize), PreprocessingError> {
     const TOK_DEFINE: &str = "%define";
 
-    process_line_starting_with_directive(src, TOK_DEFINE, |line| {
+    process_line_starting_with_directive(src, TOK_DEFINE, |line, _| {
         parse_define(line, defines)?;
         Ok(String::new())
     })
@@ -149,9 +305,7 @@ fn parse_define(
     let (key, value) = line.split_at(first_space);
     let value = value.trim();
 
-    match defines.0.entry(
-        CString::new(key).expect("The text shouldn't contain null bytes"),
-    ) {
+    match defines.0.entry(key.to_string()) {
         // the happy case, this symbol hasn't been defined before so we can just
         // insert it.
         Entry::Vacant(vacant) => {
@@ -189,14 +343,49 @@ pub enum PreprocessingError {
     DefineWithoutValue(String),
 }
 
+impl Display for PreprocessingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PreprocessingError::FailedInclude { name, inner } => {
+                write!(f, "Unable to include \"{}\": {}", name, inner)
+            },
+            PreprocessingError::DuplicateDefine {
+                name,
+                original_value,
+                new_value,
+            } => write!(
+                f,
+                "\"{}\" is already defined as \"{}\" (new value: \"{}\")",
+                name, original_value, new_value
+            ),
+            PreprocessingError::EmptyDefine => {
+                write!(f, "A %define is missing its name")
+            },
+            PreprocessingError::DefineWithoutValue(name) => {
+                write!(f, "\"{}\" is defined without a value", name)
+            },
+        }
+    }
+}
+
+impl Error for PreprocessingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PreprocessingError::FailedInclude { inner, .. } => Some(inner),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ffi;
+    use crate::htab::{tvm_htab_create, tvm_htab_destroy, tvm_htab_find_ref};
     use std::{
         ffi::{CStr, CString},
         io::Write,
         os::raw::c_int,
+        ptr,
     };
     use tempfile::NamedTempFile;
 
@@ -264,9 +453,7 @@ mod tests {
         assert_eq!(got, "\n");
         assert_eq!(num_defines, 1);
         assert_eq!(hashtable.0.len(), 1);
-        let key = CString::new("key").unwrap();
-        let item = hashtable.0.get(&key).unwrap();
-        assert_eq!(item.opaque_value_str().unwrap(), "value");
+        assert_eq!(hashtable.get_str("key").unwrap(), "value");
     }
 
     #[test]
@@ -281,9 +468,9 @@ mod tests {
             // get a copy of `src` that was allocated using C's malloc
             let mut src = libc::strdup(src.as_ptr());
             let mut len = original_length as c_int;
-            let defines = ffi::tvm_htab_create();
+            let defines = tvm_htab_create();
 
-            let ret = ffi::tvm_preprocess(&mut src, &mut len, defines);
+            let ret = tvm_preprocess(&mut src, &mut len, defines.cast());
 
             // preprocessing should have been successful
             assert_eq!(ret, 0);
@@ -296,20 +483,71 @@ mod tests {
 
             // make sure the "true" and "FOO_BAR" defines were set
             let true_define =
-                ffi::tvm_htab_find_ref(defines, b"true\0".as_ptr().cast());
+                tvm_htab_find_ref(defines, b"true\0".as_ptr().cast());
             let got = CStr::from_ptr(true_define).to_str().unwrap();
             assert_eq!(got, "1");
             let foo_bar =
-                ffi::tvm_htab_find_ref(defines, b"FOO_BAR\0".as_ptr().cast());
+                tvm_htab_find_ref(defines, b"FOO_BAR\0".as_ptr().cast());
             let got = CStr::from_ptr(foo_bar).to_str().unwrap();
             assert_eq!(got, "-42");
 
             // clean up our hashtable and copied source text
-            ffi::tvm_htab_destroy(defines);
+            tvm_htab_destroy(defines);
             libc::free(src.cast());
         }
     }
 
+    #[test]
+    fn builtins_are_defined_before_processing() {
+        let builtins = Builtins {
+            file: Some(String::from("main.vm")),
+            memory_size: 1024,
+        };
+        let mut hashtable = HashTable::default();
+
+        preprocess_with_builtins(String::new(), &mut hashtable, &builtins)
+            .unwrap();
+
+        assert_eq!(hashtable.get_str("__TVM_MEM_SIZE__").unwrap(), "1024");
+        assert_eq!(
+            hashtable.get_str("__TVM_VERSION__").unwrap(),
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    #[test]
+    fn builtins_cant_be_redefined() {
+        let src = String::from("%define __TVM_MEM_SIZE__ 42\n");
+        let mut hashtable = HashTable::default();
+
+        let err = preprocess(src, &mut hashtable).unwrap_err();
+
+        match err {
+            PreprocessingError::DuplicateDefine { name, .. } => {
+                assert_eq!(name, "__TVM_MEM_SIZE__")
+            },
+            other => panic!("Expected DuplicateDefine, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn expand_line_numbers_in_place() {
+        let src = "prn __LINE__\n\nmov eax, __LINE__\nFOO__LINE__";
+
+        let got = expand_builtins(src, "main.vm");
+
+        assert_eq!(got, "prn 1\n\nmov eax, 3\nFOO__LINE__");
+    }
+
+    #[test]
+    fn expand_file_names_in_place() {
+        let src = "; __FILE__:__LINE__\nFOO__FILE__";
+
+        let got = expand_builtins(src, "main.vm");
+
+        assert_eq!(got, "; main.vm:1\nFOO__FILE__");
+    }
+
     #[test]
     fn include_another_file() {
         const TOP_LEVEL: &str = "first line\n%include nested\nlast line\n";
@@ -330,10 +568,10 @@ mod tests {
             // create a copy of the top_level_src which can be freed by C
             let mut src = libc::strdup(top_level_src.as_ptr());
             let mut len = libc::strlen(src) as c_int;
-            let defines = ffi::tvm_htab_create();
+            let defines = tvm_htab_create();
 
             // after all that setup code we can *finally* call the preprocessor
-            let ret = ffi::tvm_preprocess(&mut src, &mut len, defines);
+            let ret = tvm_preprocess(&mut src, &mut len, defines.cast());
 
             assert_eq!(ret, 0);
 
@@ -346,8 +584,108 @@ mod tests {
             // been removed
             assert_eq!(got, "first line\nnested\nlast line\n");
 
-            ffi::tvm_htab_destroy(defines);
+            tvm_htab_destroy(defines);
             libc::free(src as *mut _);
         }
     }
+
+    #[test]
+    fn included_files_have_their_own_line_numbers() {
+        let mut nested = NamedTempFile::new().unwrap();
+        nested.write_all(b"first\nprn __LINE__").unwrap();
+        let src = format!(
+            "prn __LINE__\n%include {}\nprn __LINE__\n",
+            nested.path().display()
+        );
+        let mut hashtable = HashTable::default();
+
+        let got = preprocess(src, &mut hashtable).unwrap();
+
+        assert_eq!(got, "prn 1\nfirst\nprn 2\nprn 3\n");
+    }
+
+    #[test]
+    fn included_files_have_their_own_file_names() {
+        let mut nested = NamedTempFile::new().unwrap();
+        nested.write_all(b"; __FILE__").unwrap();
+        let nested_filename = nested.path().display().to_string();
+        let src = format!("; __FILE__\n%include {}\n", nested_filename);
+        let builtins = Builtins {
+            file: Some(String::from("main.vm")),
+            ..Builtins::default()
+        };
+        let mut hashtable = HashTable::default();
+
+        let got =
+            preprocess_with_builtins(src, &mut hashtable, &builtins).unwrap();
+
+        assert_eq!(got, format!("; main.vm\n; {}\n", nested_filename));
+        assert!(hashtable.get("__FILE__").is_none());
+    }
+
+    #[test]
+    fn file_name_defaults_to_source() {
+        let mut hashtable = HashTable::default();
+
+        let got = preprocess(String::from("; __FILE__"), &mut hashtable);
+
+        assert_eq!(got.unwrap(), "; <source>");
+    }
+
+    #[test]
+    fn preprocess_rejects_null_pointers() {
+        let src = CString::new("nop").unwrap();
+        let mut len = 3;
+
+        unsafe {
+            let mut src = libc::strdup(src.as_ptr());
+            let defines = tvm_htab_create();
+
+            let null_src: *mut *mut _ = ptr::null_mut();
+            assert_eq!(tvm_preprocess(null_src, &mut len, defines.cast()), -1);
+            assert_eq!(
+                tvm_preprocess(&mut src, ptr::null_mut(), defines.cast()),
+                -1
+            );
+            assert_eq!(tvm_preprocess(&mut src, &mut len, ptr::null_mut()), -1);
+            let mut null_str = ptr::null_mut();
+            assert_eq!(
+                tvm_preprocess(&mut null_str, &mut len, defines.cast()),
+                -1
+            );
+
+            tvm_htab_destroy(defines);
+            libc::free(src.cast());
+        }
+    }
+
+    #[test]
+    fn map_included_lines_back_to_their_files() {
+        let mut nested = NamedTempFile::new().unwrap();
+        nested.write_all(b"nop\nnop").unwrap();
+        let nested_filename = nested.path().display().to_string();
+        let src = format!("%define x 1\n%include {}\nprn x\n", nested_filename);
+        let builtins = Builtins {
+            file: Some(String::from("main.vm")),
+            ..Builtins::default()
+        };
+        let mut hashtable = HashTable::default();
+
+        let (got, source_map) =
+            preprocess_with_source_map(src, &mut hashtable, &builtins).unwrap();
+
+        assert_eq!(got, "\nnop\nnop\nprn x\n");
+        let locations: Vec<_> = (1..=4)
+            .map(|line| source_map.lookup(line).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                String::from("main.vm:1"),
+                format!("{}:1", nested_filename),
+                format!("{}:2", nested_filename),
+                String::from("main.vm:3"),
+            ]
+        );
+    }
 }
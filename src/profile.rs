@@ -0,0 +1,266 @@
+use crate::{program::Program, Opcode};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    io::{self, Write},
+    mem,
+};
+
+/// Execution counts gathered while profiling a program.
+///
+/// Counts accumulate across runs until the profile is reset with
+/// [`crate::Vm::start_profiling()`] or a new program is loaded.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Profile {
+    /// The number of times each instruction was executed.
+    counts: Vec<u64>,
+    /// The name of the label at each labelled instruction.
+    labels: BTreeMap<usize, String>,
+    /// The index of each function (i.e. `call` target) on the call stack,
+    /// starting with the program's entry point.
+    call_stack: Vec<usize>,
+    /// The number of instructions executed with each call stack.
+    stacks: BTreeMap<Vec<usize>, u64>,
+    /// Instructions executed with the current call stack which haven't been
+    /// added to `stacks` yet, so the stack is only copied when it changes.
+    current: u64,
+}
+
+impl Profile {
+    pub(crate) fn new(program: &Program) -> Profile {
+        let mut labels = BTreeMap::new();
+
+        for (name, &index) in &program.labels {
+            // labels are visited alphabetically, so the first one wins when
+            // several point at the same instruction
+            labels.entry(index).or_insert_with(|| name.clone());
+        }
+
+        Profile {
+            counts: vec![0; program.len()],
+            labels,
+            call_stack: Vec::new(),
+            stacks: BTreeMap::new(),
+            current: 0,
+        }
+    }
+
+    /// Called when a new run starts from the entry point.
+    pub(crate) fn begin(&mut self, start: usize) {
+        self.flush();
+        self.call_stack.clear();
+        self.call_stack.push(start);
+    }
+
+    /// Record that `opcode` was executed at `eip`, leaving `eip` pointing at
+    /// `next`.
+    pub(crate) fn record(&mut self, eip: usize, opcode: Opcode, next: usize) {
+        self.counts[eip] += 1;
+        self.current += 1;
+
+        match opcode {
+            Opcode::Call => {
+                self.flush();
+                self.call_stack.push(next);
+            },
+            // an unbalanced ret leaves us in the entry point's frame
+            Opcode::Ret if self.call_stack.len() > 1 => {
+                self.flush();
+                self.call_stack.pop();
+            },
+            _ => {},
+        }
+    }
+
+    /// Add the instructions executed with the current call stack to
+    /// `stacks`.
+    fn flush(&mut self) {
+        let count = mem::take(&mut self.current);
+
+        if count > 0 {
+            *self.stacks.entry(self.call_stack.clone()).or_insert(0) += count;
+        }
+    }
+
+    /// How many times the instruction at `index` was executed.
+    pub fn count(&self, index: usize) -> u64 {
+        self.counts.get(index).copied().unwrap_or(0)
+    }
+
+    /// The total number of instructions executed.
+    pub fn total(&self) -> u64 { self.counts.iter().sum() }
+
+    /// The `n` most frequently executed instructions, most frequent first.
+    pub fn hot_spots(&self, n: usize) -> Vec<HotSpot> {
+        let mut hot_spots: Vec<HotSpot> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(instruction, &count)| HotSpot { instruction, count })
+            .collect();
+
+        hot_spots.sort_by_key(|h| (Reverse(h.count), h.instruction));
+        hot_spots.truncate(n);
+        hot_spots
+    }
+
+    /// Execution counts for each label region, most frequent first.
+    ///
+    /// A region runs from a label to the instruction before the next label.
+    /// Any instructions before the first label are reported with a `label`
+    /// of `None`.
+    pub fn regions(&self) -> Vec<Region> {
+        let mut starts: Vec<(usize, Option<String>)> = self
+            .labels
+            .iter()
+            .map(|(&index, name)| (index, Some(name.clone())))
+            .collect();
+        if starts.first().map(|(index, _)| *index) != Some(0) {
+            starts.insert(0, (0, None));
+        }
+
+        let mut regions: Vec<Region> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, (start, label))| {
+                let end = starts
+                    .get(i + 1)
+                    .map(|(next, _)| *next)
+                    .unwrap_or(self.counts.len());
+                let end = usize::max(*start, end);
+
+                Region {
+                    label: label.clone(),
+                    start: *start,
+                    end,
+                    count: self.counts[*start..end].iter().sum(),
+                }
+            })
+            .filter(|region| region.start < region.end)
+            .collect();
+
+        regions.sort_by_key(|r| (Reverse(r.count), r.start));
+        regions
+    }
+
+    /// Write the call stacks seen while profiling in the "folded stacks"
+    /// format used by [flamegraph.pl][fg] and `inferno`.
+    ///
+    /// Each function is named after the label at its entry point (e.g.
+    /// `start;fib;fib 42`), and the count is the number of instructions
+    /// executed in that stack.
+    ///
+    /// [fg]: https://github.com/brendangregg/FlameGraph
+    pub fn write_folded<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut stacks = self.stacks.clone();
+        if self.current > 0 {
+            *stacks.entry(self.call_stack.clone()).or_insert(0) += self.current;
+        }
+
+        for (stack, count) in &stacks {
+            let names: Vec<String> = stack
+                .iter()
+                .map(|&index| self.function_name(index))
+                .collect();
+            writeln!(writer, "{} {}", names.join(";"), count)?;
+        }
+
+        Ok(())
+    }
+
+    fn function_name(&self, index: usize) -> String {
+        match self.labels.get(&index) {
+            Some(name) => name.clone(),
+            None => format!("{:#x}", index),
+        }
+    }
+}
+
+/// An instruction and the number of times it was executed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HotSpot {
+    pub instruction: usize,
+    pub count: u64,
+}
+
+/// The instructions from one label up to (but not including) the next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub label: Option<String>,
+    /// The index of the region's first instruction.
+    pub start: usize,
+    /// The index one past the region's last instruction.
+    pub end: usize,
+    /// The total number of instructions executed in this region.
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HashTable;
+
+    #[test]
+    fn count_regions_and_stacks() {
+        let src = "nop\nstart:\n  call double\n  call double\n  jmp end\n\
+                   double:\n  add eax, eax\n  ret\nend:\n";
+        let program = crate::parser::parse(src, &HashTable::new()).unwrap();
+        let mut profile = Profile::new(&program);
+
+        // start -> double -> start -> double -> start
+        profile.begin(1);
+        for &(eip, next) in &[(1, 4), (4, 5), (5, 2), (2, 4), (4, 5), (5, 3)] {
+            let opcode = program.instructions[eip].opcode;
+            profile.record(eip, opcode, next);
+        }
+
+        assert_eq!(profile.total(), 6);
+        assert_eq!(profile.count(4), 2);
+        assert_eq!(
+            profile.hot_spots(1),
+            vec![HotSpot {
+                instruction: 4,
+                count: 2
+            }]
+        );
+
+        let regions: Vec<_> = profile
+            .regions()
+            .into_iter()
+            .map(|r| (r.label, r.count))
+            .collect();
+        assert_eq!(
+            regions,
+            vec![
+                (Some(String::from("double")), 4),
+                (Some(String::from("start")), 2),
+                (None, 0),
+            ]
+        );
+
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "start 2\nstart;double 4\n"
+        );
+    }
+
+    #[test]
+    fn include_the_stack_which_is_still_running() {
+        let src = "start:\n  call f\n  nop\nf:\n  nop\n  nop\n";
+        let program = crate::parser::parse(src, &HashTable::new()).unwrap();
+        let mut profile = Profile::new(&program);
+
+        profile.begin(0);
+        for &(eip, next) in &[(0, 2), (2, 3), (3, 4)] {
+            let opcode = program.instructions[eip].opcode;
+            profile.record(eip, opcode, next);
+        }
+
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "start 1\nstart;f 2\n");
+    }
+}
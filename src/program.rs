@@ -0,0 +1,205 @@
+use crate::{
+    parser,
+    preprocessing::{preprocess_with_source_map, Builtins},
+    HashTable, LoadError, Opcode, Register, SourceMap,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    path::Path,
+};
+
+/// A parsed program, ready to be executed (the equivalent of `tvm_prog`).
+///
+/// Programs are immutable once loaded, so a single program can be wrapped
+/// in an [`std::sync::Arc`] and shared between any number of VMs with
+/// [`crate::Vm::load_program()`].
+///
+/// ```rust
+/// # use std::io::Write;
+/// use std::sync::Arc;
+/// use tinyvm::{Program, Register, Vm};
+///
+/// # let mut file = tempfile::NamedTempFile::new().unwrap();
+/// # writeln!(file, "start: add eax, 2").unwrap();
+/// # let path = file.path();
+/// let program = Arc::new(Program::load(path).unwrap());
+///
+/// for _ in 0..3 {
+///     let mut vm = Vm::new();
+///     vm.load_program(Arc::clone(&program));
+///     vm.run().unwrap();
+///     assert_eq!(vm.reg(Register::Eax), 2);
+/// }
+/// ```
+///
+/// With the `serde` feature enabled, a program (including its label table)
+/// can be serialized, e.g. to cache the result of parsing.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub(crate) instructions: Vec<Instruction>,
+    /// The index of the first instruction to execute.
+    pub(crate) start: usize,
+    /// The index of the instruction each label refers to.
+    pub(crate) labels: BTreeMap<String, usize>,
+    pub(crate) source_map: SourceMap,
+}
+
+impl Program {
+    /// Read, preprocess, and parse the program at `path`.
+    ///
+    /// `__TVM_MEM_SIZE__` is defined as [`crate::DEFAULT_MEMORY_SIZE`]. Use
+    /// [`Program::parse()`] if the program will run with a different amount
+    /// of memory.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Program, LoadError> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path).map_err(LoadError::Io)?;
+        let builtins = Builtins {
+            file: Some(path.display().to_string()),
+            ..Builtins::default()
+        };
+
+        Program::parse(src, &builtins)
+    }
+
+    /// Preprocess and parse some source code.
+    ///
+    /// Any `%include`s are resolved relative to the current directory.
+    pub fn parse<S: Into<String>>(
+        src: S,
+        builtins: &Builtins,
+    ) -> Result<Program, LoadError> {
+        let mut defines = HashTable::new();
+        let (src, source_map) =
+            preprocess_with_source_map(src.into(), &mut defines, builtins)
+                .map_err(LoadError::Preprocessing)?;
+        let mut program =
+            parser::parse(&src, &defines).map_err(LoadError::Parse)?;
+        program.source_map = source_map;
+
+        Ok(program)
+    }
+
+    /// Look up the instruction at a particular index.
+    pub(crate) fn get(&self, index: usize) -> Option<&Instruction> {
+        self.instructions.get(index)
+    }
+
+    /// The number of instructions in the program.
+    pub fn len(&self) -> usize { self.instructions.len() }
+
+    pub fn is_empty(&self) -> bool { self.instructions.is_empty() }
+
+    pub fn instructions(&self) -> &[Instruction] { &self.instructions }
+
+    /// The index of the first instruction to execute (i.e. the `start`
+    /// label, or `0` if there isn't one).
+    pub fn start(&self) -> usize { self.start }
+
+    /// Look up the index of the instruction a label refers to.
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).copied()
+    }
+
+    /// Every label in the program, and the index of the instruction it
+    /// refers to.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.labels
+            .iter()
+            .map(|(name, &index)| (name.as_str(), index))
+    }
+
+    /// Maps the [`Instruction::line`] of each instruction back to the file
+    /// and line it was loaded from.
+    pub fn source_map(&self) -> &SourceMap { &self.source_map }
+
+    /// A 64-bit FNV-1a hash of the instructions and entry point, used to
+    /// check a [`crate::Snapshot`] is restored into the same program.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+
+        let mut text = format!("start {}\n", self.start);
+        for instruction in &self.instructions {
+            text.push_str(&instruction.to_string());
+            text.push('\n');
+        }
+
+        text.bytes().fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        })
+    }
+}
+
+/// A single instruction and its operands.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Instruction {
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+    /// The (1-based) line in the preprocessed source this instruction came
+    /// from. Use a [`crate::SourceMap`] to find the original file and line.
+    pub line: usize,
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode)?;
+
+        for (i, operand) in self.operands.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", separator, operand)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Something an instruction can read from or write to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Operand {
+    Register(Register),
+    /// A constant. Labels are resolved to the index of the instruction they
+    /// point at.
+    Value(i32),
+    /// An index into memory, treated as an array of 32-bit integers (i.e.
+    /// `[1]` refers to the bytes at addresses `4..8`).
+    Memory(u32),
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(reg) => write!(f, "{}", reg),
+            Operand::Value(value) => write!(f, "{}", value),
+            Operand::Memory(index) => write!(f, "[{}]", index),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_through_json() {
+        let src = "start:\n  mov eax, [1]\n  jmp end\nend:\n";
+        let program = Program::parse(src, &Builtins::default()).unwrap();
+
+        let json = serde_json::to_value(&program).unwrap();
+        let got: Program = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(json["labels"], serde_json::json!({ "start": 0, "end": 2 }));
+        assert_eq!(
+            json["instructions"][0]["operands"],
+            serde_json::json!([{ "register": "eax" }, { "memory": 1 }])
+        );
+        assert_eq!(got, program);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+/// One of the VM's registers.
+///
+/// The variants are declared in the same order as the register enum in
+/// `tvm_memory.h`, so `register as usize` can be used to index into the
+/// `registers` array of the VM's `Cpu`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+    Esi,
+    Edi,
+    /// The stack pointer.
+    Esp,
+    /// The base pointer.
+    Ebp,
+    /// The instruction pointer (an index into the program's instructions).
+    Eip,
+    R08,
+    R09,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Register {
+    /// Every register, in declaration order.
+    pub const ALL: [Register; 17] = [
+        Register::Eax,
+        Register::Ebx,
+        Register::Ecx,
+        Register::Edx,
+        Register::Esi,
+        Register::Edi,
+        Register::Esp,
+        Register::Ebp,
+        Register::Eip,
+        Register::R08,
+        Register::R09,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+        Register::R15,
+    ];
+
+    /// The name used to refer to this register in assembly.
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::Eax => "eax",
+            Register::Ebx => "ebx",
+            Register::Ecx => "ecx",
+            Register::Edx => "edx",
+            Register::Esi => "esi",
+            Register::Edi => "edi",
+            Register::Esp => "esp",
+            Register::Ebp => "ebp",
+            Register::Eip => "eip",
+            Register::R08 => "r08",
+            Register::R09 => "r09",
+            Register::R10 => "r10",
+            Register::R11 => "r11",
+            Register::R12 => "r12",
+            Register::R13 => "r13",
+            Register::R14 => "r14",
+            Register::R15 => "r15",
+        }
+    }
+}
+
+impl Display for Register {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Register {
+    type Err = UnknownRegister;
+
+    fn from_str(s: &str) -> Result<Register, UnknownRegister> {
+        Register::ALL
+            .iter()
+            .copied()
+            .find(|reg| reg.name() == s)
+            .ok_or_else(|| UnknownRegister(s.to_string()))
+    }
+}
+
+/// The error returned when parsing a [`Register`] from an unknown name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownRegister(pub String);
+
+impl Display for UnknownRegister {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown register, \"{}\"", self.0)
+    }
+}
+
+impl Error for UnknownRegister {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_register_names() {
+        for &register in Register::ALL.iter() {
+            let got: Register = register.name().parse().unwrap();
+
+            assert_eq!(got, register);
+        }
+    }
+
+    #[test]
+    fn registers_are_in_libtvm_order() {
+        for (i, register) in Register::ALL.iter().enumerate() {
+            assert_eq!(*register as usize, i);
+        }
+    }
+
+    #[test]
+    fn unknown_register() {
+        let err = "rax".parse::<Register>().unwrap_err();
+
+        assert_eq!(err, UnknownRegister(String::from("rax")));
+        assert_eq!(err.to_string(), "Unknown register, \"rax\"");
+    }
+}
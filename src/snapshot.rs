@@ -0,0 +1,353 @@
+use crate::{
+    memory::{PagedMemory, PAGE_SIZE},
+    Memory, Register,
+};
+use std::{
+    convert::TryInto,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io::{self, Error as IoError, Read, Write},
+};
+
+/// The bytes every encoded [`Snapshot`] starts with.
+const MAGIC: [u8; 4] = *b"TVMS";
+
+/// The version of the binary format written by [`Snapshot::write_to()`].
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// An image of a VM's registers, flags, and memory, created with
+/// [`crate::Vm::snapshot()`] and restored with [`crate::Vm::restore()`].
+///
+/// Snapshots don't include the program itself, so the same program must be
+/// loaded before restoring one.
+///
+/// # Binary Format
+///
+/// All integers are little-endian.
+///
+/// | Field               | Type              |
+/// | ------------------- | ----------------- |
+/// | magic (`TVMS`)      | `[u8; 4]`         |
+/// | version             | `u32`             |
+/// | program fingerprint | `u64`             |
+/// | registers           | `[i32; 17]`       |
+/// | FLAGS               | `i32`             |
+/// | remainder           | `i32`             |
+/// | stack top           | `i32`             |
+/// | mid-run             | `u8` (0 or 1)     |
+/// | memory size         | `u64`             |
+/// | page count          | `u64`             |
+/// | pages               | `[Page; count]`   |
+///
+/// Only pages of memory which have been written to are included, in
+/// ascending order. Each page is a `u64` index followed by its 4096 bytes,
+/// and bytes past the end of memory must be zero.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub(crate) program: u64,
+    pub(crate) registers: [i32; Register::ALL.len()],
+    pub(crate) flags: i32,
+    pub(crate) remainder: i32,
+    pub(crate) stack_top: i32,
+    /// Whether a run was in progress, so restoring resumes it.
+    pub(crate) suspended: bool,
+    pub(crate) memory: PagedMemory,
+}
+
+impl Snapshot {
+    /// The value a register had when the snapshot was taken.
+    pub fn reg(&self, register: Register) -> i32 {
+        self.registers[register as usize]
+    }
+
+    /// The VM's memory when the snapshot was taken.
+    pub fn memory(&self) -> Memory<'_> { Memory::new(&self.memory) }
+
+    /// Encode the snapshot using the versioned binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.write_to(&mut buffer)
+            .expect("Writing to a Vec<u8> can't fail");
+        buffer
+    }
+
+    /// Decode a snapshot created with [`Snapshot::to_bytes()`].
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let snapshot = Snapshot::read_from(&mut bytes)?;
+
+        if bytes.is_empty() {
+            Ok(snapshot)
+        } else {
+            Err(SnapshotError::Invalid("Trailing bytes after the snapshot"))
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&self.program.to_le_bytes())?;
+        for register in &self.registers {
+            writer.write_all(&register.to_le_bytes())?;
+        }
+        writer.write_all(&self.flags.to_le_bytes())?;
+        writer.write_all(&self.remainder.to_le_bytes())?;
+        writer.write_all(&self.stack_top.to_le_bytes())?;
+        writer.write_all(&[self.suspended as u8])?;
+        writer.write_all(&(self.memory.len() as u64).to_le_bytes())?;
+        let num_pages = self.memory.allocated_pages() as u64;
+        writer.write_all(&num_pages.to_le_bytes())?;
+        for (index, page) in self.memory.allocated() {
+            writer.write_all(&(index as u64).to_le_bytes())?;
+            writer.write_all(page)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(
+        mut reader: R,
+    ) -> Result<Snapshot, SnapshotError> {
+        if read_array::<_, 4>(&mut reader)? != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+
+        let version = u32::from_le_bytes(read_array(&mut reader)?);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let program = u64::from_le_bytes(read_array(&mut reader)?);
+        let mut registers = [0; Register::ALL.len()];
+        for register in &mut registers {
+            *register = i32::from_le_bytes(read_array(&mut reader)?);
+        }
+        let flags = i32::from_le_bytes(read_array(&mut reader)?);
+        let remainder = i32::from_le_bytes(read_array(&mut reader)?);
+        let stack_top = i32::from_le_bytes(read_array(&mut reader)?);
+        let suspended = match read_array::<_, 1>(&mut reader)? {
+            [0] => false,
+            [1] => true,
+            _ => return Err(SnapshotError::Invalid("Invalid mid-run flag")),
+        };
+
+        let memory_size = u64::from_le_bytes(read_array(&mut reader)?);
+        let memory_size: usize = memory_size
+            .try_into()
+            .ok()
+            .filter(|&size| size <= i32::MAX as usize)
+            .ok_or(SnapshotError::Invalid("The memory is too big"))?;
+        let num_pages = u64::from_le_bytes(read_array(&mut reader)?);
+        if num_pages > memory_size.div_ceil(PAGE_SIZE) as u64 {
+            return Err(SnapshotError::Invalid("Too many pages"));
+        }
+        // read one page at a time so a corrupted count can't make us
+        // allocate a huge buffer up front
+        let mut pages = Vec::new();
+        let mut previous = None;
+        for _ in 0..num_pages {
+            let index = u64::from_le_bytes(read_array(&mut reader)?);
+            if previous.is_some_and(|previous| index <= previous) {
+                return Err(SnapshotError::Invalid("Pages are out of order"));
+            }
+            previous = Some(index);
+            let index = index.try_into().map_err(|_| {
+                SnapshotError::Invalid("Page is outside memory")
+            })?;
+            pages.push((index, read_array::<_, PAGE_SIZE>(&mut reader)?));
+        }
+        let memory = PagedMemory::from_pages(memory_size, pages)
+            .ok_or(SnapshotError::Invalid("Page is outside memory"))?;
+
+        if stack_top < 0 || stack_top as usize > memory_size {
+            return Err(SnapshotError::Invalid("The stack is outside memory"));
+        }
+
+        Ok(Snapshot {
+            program,
+            registers,
+            flags,
+            remainder,
+            stack_top,
+            suspended,
+            memory,
+        })
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // the memory is usually megabytes of zeroes, so leave it out
+        f.debug_struct("Snapshot")
+            .field("program", &format_args!("{:#018x}", self.program))
+            .field("registers", &self.registers)
+            .field("flags", &self.flags)
+            .field("remainder", &self.remainder)
+            .field("stack_top", &self.stack_top)
+            .field("suspended", &self.suspended)
+            .field("memory_size", &self.memory.len())
+            .finish()
+    }
+}
+
+fn read_array<R: Read, const N: usize>(
+    reader: &mut R,
+) -> Result<[u8; N], SnapshotError> {
+    let mut buffer = [0; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// The reasons a [`Snapshot`] may fail to be decoded or restored.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(IoError),
+    /// The data doesn't start with the snapshot magic number.
+    NotASnapshot,
+    /// The snapshot was written by a newer (or older) version of the format.
+    UnsupportedVersion(u32),
+    /// The snapshot was decoded, but its contents don't make sense.
+    Invalid(&'static str),
+    /// The snapshot was taken while running a different program.
+    ProgramMismatch,
+}
+
+impl From<IoError> for SnapshotError {
+    fn from(e: IoError) -> SnapshotError { SnapshotError::Io(e) }
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "I/O error: {}", e),
+            SnapshotError::NotASnapshot => write!(f, "Not a TinyVM snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported snapshot version {} (expected {})",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Invalid(reason) => {
+                write!(f, "Invalid snapshot: {}", reason)
+            },
+            SnapshotError::ProgramMismatch => {
+                write!(f, "The snapshot was taken from a different program")
+            },
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let mut registers = [0; Register::ALL.len()];
+        registers[Register::Eax as usize] = -42;
+        registers[Register::Esp as usize] = 12;
+
+        Snapshot {
+            program: 0xdead_beef,
+            registers,
+            flags: 2,
+            remainder: 1,
+            stack_top: 16,
+            suspended: true,
+            memory: PagedMemory::from_bytes(&(0..32).collect::<Vec<u8>>()),
+        }
+    }
+
+    #[test]
+    fn round_trip_through_bytes() {
+        let original = snapshot();
+
+        let bytes = original.to_bytes();
+        let got = Snapshot::from_bytes(&bytes).unwrap();
+
+        assert_eq!(&bytes[..4], b"TVMS");
+        assert_eq!(got, original);
+        assert_eq!(got.reg(Register::Eax), -42);
+    }
+
+    #[test]
+    fn reject_invalid_snapshots() {
+        let bytes = snapshot().to_bytes();
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 99;
+        assert!(matches!(
+            Snapshot::from_bytes(&wrong_version),
+            Err(SnapshotError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            Snapshot::from_bytes(b"ELF\x7f"),
+            Err(SnapshotError::NotASnapshot)
+        ));
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Io(_))
+        ));
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(matches!(
+            Snapshot::from_bytes(&trailing),
+            Err(SnapshotError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn only_allocated_pages_are_written() {
+        let mut original = snapshot();
+        original.memory = PagedMemory::new(crate::DEFAULT_MEMORY_SIZE);
+        crate::MemoryMut::new(&mut original.memory)
+            .write_bytes(5 * PAGE_SIZE + 1, &[42])
+            .unwrap();
+
+        let bytes = original.to_bytes();
+        let got = Snapshot::from_bytes(&bytes).unwrap();
+
+        // the header, the memory size and page count, then a single page
+        assert_eq!(bytes.len(), 97 + 8 + 8 + 8 + PAGE_SIZE);
+        assert_eq!(got, original);
+        assert_eq!(got.memory.allocated_pages(), 1);
+    }
+
+    #[test]
+    fn reject_invalid_pages() {
+        let bytes = snapshot().to_bytes();
+        let page_index = 97 + 8 + 8;
+
+        let mut outside_memory = bytes.clone();
+        outside_memory[page_index] = 1;
+        let mut past_the_end = bytes;
+        past_the_end[page_index + 8 + 32] = 1;
+
+        for bytes in &[outside_memory, past_the_end] {
+            assert!(matches!(
+                Snapshot::from_bytes(bytes),
+                Err(SnapshotError::Invalid(_))
+            ));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialized_memory_is_sparse() {
+        let original = snapshot();
+
+        let json = serde_json::to_value(&original).unwrap();
+        let got: Snapshot = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(json["memory"]["len"], 32);
+        assert_eq!(json["memory"]["pages"][0][0], 0);
+        assert_eq!(got, original);
+    }
+}
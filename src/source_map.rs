@@ -0,0 +1,108 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Maps lines in preprocessed source code back to the file and line they
+/// originally came from.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceMap {
+    /// The location of each line in the preprocessed text.
+    lines: Vec<SourceLocation>,
+}
+
+impl SourceMap {
+    /// Create a source map for `src`, where no lines have been moved around
+    /// yet.
+    pub(crate) fn new(file: &str, src: &str) -> SourceMap {
+        let num_lines = src.split('\n').count();
+
+        SourceMap {
+            lines: (1..=num_lines)
+                .map(|line| SourceLocation::new(file, line))
+                .collect(),
+        }
+    }
+
+    /// Record that the line at `index` (0-based) was replaced by the
+    /// `num_lines` lines from `file`.
+    pub(crate) fn include(
+        &mut self,
+        index: usize,
+        file: &str,
+        num_lines: usize,
+    ) {
+        let included =
+            (1..=num_lines).map(|line| SourceLocation::new(file, line));
+        let end = usize::min(index + 1, self.lines.len());
+        let _ = self.lines.splice(index..end, included);
+    }
+
+    /// Find where a (1-based) line in the preprocessed source came from.
+    pub fn lookup(&self, line: usize) -> Option<&SourceLocation> {
+        line.checked_sub(1).and_then(|index| self.lines.get(index))
+    }
+
+    /// The number of lines in the preprocessed source.
+    pub fn len(&self) -> usize { self.lines.len() }
+
+    pub fn is_empty(&self) -> bool { self.lines.is_empty() }
+
+    /// Find the first line in the preprocessed source which came from
+    /// `location`.
+    pub fn find(&self, location: &SourceLocation) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|loc| loc == location)
+            .map(|index| index + 1)
+    }
+}
+
+/// A (1-based) line in a particular file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+}
+
+impl SourceLocation {
+    pub fn new<S: Into<String>>(file: S, line: usize) -> SourceLocation {
+        SourceLocation {
+            file: file.into(),
+            line,
+        }
+    }
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splice_in_an_included_file() {
+        let mut map = SourceMap::new("main.vm", "first\n%include foo\nlast");
+
+        map.include(1, "foo.vm", 2);
+
+        assert_eq!(map.len(), 4);
+        let got: Vec<_> = (1..=4)
+            .map(|line| map.lookup(line).unwrap().clone())
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                SourceLocation::new("main.vm", 1),
+                SourceLocation::new("foo.vm", 1),
+                SourceLocation::new("foo.vm", 2),
+                SourceLocation::new("main.vm", 3),
+            ]
+        );
+        assert_eq!(map.lookup(0), None);
+        assert_eq!(map.find(&SourceLocation::new("main.vm", 3)), Some(4));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::{program::Instruction, Register, SourceLocation, VmFault};
+use std::{
+    fmt::{self, Debug, Formatter, Write as _},
+    io::{self, Write},
+};
+
+/// How each executed instruction is written to the trace.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TraceFormat {
+    /// One line per instruction, meant to be read by a person.
+    ///
+    /// ```text
+    ///    3: add eax, ebx | 1, 2 | eax: 1 -> 3
+    ///    4: div eax, ecx | 3, 0 | fault: Divide by zero
+    /// ```
+    Human,
+    /// One JSON object per instruction, separated by newlines.
+    ///
+    /// ```text
+    /// {"eip":3,"file":"main.vm","line":5,"instruction":"add eax, ebx","mnemonic":"add","operands":[1,2],"changes":{"eax":[1,3]},"status":"completed","fault":null}
+    /// ```
+    ///
+    /// A memory operand which couldn't be read is written as `null`, as are
+    /// the `file` and `line` if the instruction's source isn't known. The
+    /// `status` is `completed`, `waiting_for_input`, or `faulted`, in which
+    /// case `fault` describes what went wrong.
+    JsonLines,
+}
+
+/// The state of the VM immediately before an instruction was executed.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Before {
+    pub(crate) registers: [i32; Register::ALL.len()],
+    /// The value of each operand, or `None` if it referred to an invalid
+    /// address.
+    pub(crate) operands: Vec<Option<i32>>,
+}
+
+/// What happened when the VM tried to execute an instruction.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Status<'a> {
+    Completed,
+    /// The instruction is an `inp` which will be retried.
+    WaitingForInput,
+    Faulted(&'a VmFault),
+}
+
+/// Writes a record of each executed instruction to a sink.
+pub(crate) struct Tracer {
+    format: TraceFormat,
+    sink: Box<dyn Write + Send>,
+    /// The error which stopped us from writing any more of the trace.
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub(crate) fn new(
+        sink: Box<dyn Write + Send>,
+        format: TraceFormat,
+    ) -> Tracer {
+        Tracer {
+            format,
+            sink,
+            error: None,
+        }
+    }
+
+    /// Record that the VM tried to execute the instruction at `eip`,
+    /// changing the registers from `before` to `after`.
+    ///
+    /// Once writing to the sink fails, nothing else is written and the
+    /// error is kept for [`Tracer::error()`].
+    pub(crate) fn record(
+        &mut self,
+        eip: usize,
+        instruction: &Instruction,
+        location: Option<&SourceLocation>,
+        before: &Before,
+        after: &[i32],
+        status: Status<'_>,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let line = match self.format {
+            TraceFormat::Human => {
+                human(eip, instruction, before, after, status)
+            },
+            TraceFormat::JsonLines => {
+                json(eip, instruction, location, before, after, status)
+            },
+        };
+
+        if let Err(e) = writeln!(self.sink, "{}", line) {
+            self.error = Some(e);
+        }
+    }
+
+    /// The error which stopped the trace from being written, if any.
+    pub(crate) fn error(&self) -> Option<&io::Error> { self.error.as_ref() }
+
+    /// Flush the sink, returning the error which stopped the trace from
+    /// being written (if any).
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.sink.flush(),
+        }
+    }
+}
+
+impl Debug for Tracer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// The registers (other than `eip`, which changes every time) which were
+/// modified by an instruction, and their old and new values.
+fn changes<'a>(
+    before: &'a Before,
+    after: &'a [i32],
+) -> impl Iterator<Item = (Register, i32, i32)> + 'a {
+    Register::ALL
+        .iter()
+        .copied()
+        .filter(|&reg| reg != Register::Eip)
+        .map(move |reg| {
+            (reg, before.registers[reg as usize], after[reg as usize])
+        })
+        .filter(|&(_, old, new)| old != new)
+}
+
+fn human(
+    eip: usize,
+    instruction: &Instruction,
+    before: &Before,
+    after: &[i32],
+    status: Status<'_>,
+) -> String {
+    let mut line = format!("{:>4}: {}", eip, instruction);
+
+    if !before.operands.is_empty() {
+        let values: Vec<String> = before
+            .operands
+            .iter()
+            .map(|value| match value {
+                Some(value) => value.to_string(),
+                None => String::from("?"),
+            })
+            .collect();
+        let _ = write!(line, " | {}", values.join(", "));
+    }
+
+    let changes: Vec<String> = changes(before, after)
+        .map(|(reg, old, new)| format!("{}: {} -> {}", reg, old, new))
+        .collect();
+    if !changes.is_empty() {
+        let _ = write!(line, " | {}", changes.join(", "));
+    }
+
+    match status {
+        Status::Completed => {},
+        Status::WaitingForInput => line.push_str(" | waiting for input"),
+        Status::Faulted(fault) => {
+            let _ = write!(line, " | fault: {}", fault);
+        },
+    }
+
+    line
+}
+
+fn json(
+    eip: usize,
+    instruction: &Instruction,
+    location: Option<&SourceLocation>,
+    before: &Before,
+    after: &[i32],
+    status: Status<'_>,
+) -> String {
+    // instructions only ever contain mnemonics, register names, brackets and
+    // numbers, so nothing needs escaping
+    let operands: Vec<String> = before
+        .operands
+        .iter()
+        .map(|value| match value {
+            Some(value) => value.to_string(),
+            None => String::from("null"),
+        })
+        .collect();
+    let changes: Vec<String> = changes(before, after)
+        .map(|(reg, old, new)| format!("\"{}\":[{},{}]", reg, old, new))
+        .collect();
+    let (file, line) = match location {
+        Some(location) => {
+            (json_string(&location.file), location.line.to_string())
+        },
+        None => (String::from("null"), String::from("null")),
+    };
+    let (status, fault) = match status {
+        Status::Completed => ("completed", String::from("null")),
+        Status::WaitingForInput => ("waiting_for_input", String::from("null")),
+        Status::Faulted(fault) => ("faulted", json_string(&fault.to_string())),
+    };
+
+    format!(
+        "{{\"eip\":{},\"file\":{},\"line\":{},\"instruction\":\"{}\",\"mnemonic\":\"{}\",\"operands\":[{}],\"changes\":{{{}}},\"status\":\"{}\",\"fault\":{}}}",
+        eip,
+        file,
+        line,
+        instruction,
+        instruction.opcode,
+        operands.join(","),
+        changes.join(","),
+        status,
+        fault,
+    )
+}
+
+/// Quote and escape a string so it can be used in JSON.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            },
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{program::Operand, Opcode};
+
+    fn add_eax_ebx() -> (Instruction, Before, [i32; Register::ALL.len()]) {
+        let instruction = Instruction {
+            opcode: Opcode::Add,
+            operands: vec![
+                Operand::Register(Register::Eax),
+                Operand::Register(Register::Ebx),
+            ],
+            line: 5,
+        };
+        let mut registers = [0; Register::ALL.len()];
+        registers[Register::Eax as usize] = 1;
+        registers[Register::Ebx as usize] = 2;
+        registers[Register::Eip as usize] = 3;
+        let before = Before {
+            registers,
+            operands: vec![Some(1), Some(2)],
+        };
+        let mut after = registers;
+        after[Register::Eax as usize] = 3;
+        after[Register::Eip as usize] = 4;
+
+        (instruction, before, after)
+    }
+
+    #[test]
+    fn human_readable_trace() {
+        let (instruction, before, after) = add_eax_ebx();
+
+        let got = human(3, &instruction, &before, &after, Status::Completed);
+
+        assert_eq!(got, "   3: add eax, ebx | 1, 2 | eax: 1 -> 3");
+    }
+
+    #[test]
+    fn faults_are_traced() {
+        let (instruction, before, _) = add_eax_ebx();
+        let fault = VmFault::DivideByZero;
+
+        let got = human(
+            3,
+            &instruction,
+            &before,
+            &before.registers,
+            Status::Faulted(&fault),
+        );
+
+        assert_eq!(got, "   3: add eax, ebx | 1, 2 | fault: Divide by zero");
+    }
+
+    #[test]
+    fn json_trace_is_valid_json() {
+        let (instruction, mut before, after) = add_eax_ebx();
+        before.operands[1] = None;
+
+        let location = SourceLocation::new("dir/\"quoted\".vm", 2);
+
+        let got = json(
+            3,
+            &instruction,
+            Some(&location),
+            &before,
+            &after,
+            Status::Completed,
+        );
+
+        let value: serde_json::Value = serde_json::from_str(&got).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "eip": 3,
+                "file": "dir/\"quoted\".vm",
+                "line": 2,
+                "instruction": "add eax, ebx",
+                "mnemonic": "add",
+                "operands": [1, null],
+                "changes": { "eax": [1, 3] },
+                "status": "completed",
+                "fault": null,
+            })
+        );
+    }
+
+    #[test]
+    fn json_trace_of_a_fault() {
+        let (instruction, before, _) = add_eax_ebx();
+        let fault = VmFault::Host {
+            number: 1,
+            error: "a \"quoted\"\nmessage".into(),
+        };
+
+        let got = json(
+            3,
+            &instruction,
+            None,
+            &before,
+            &before.registers,
+            Status::Faulted(&fault),
+        );
+
+        let value: serde_json::Value = serde_json::from_str(&got).unwrap();
+        assert_eq!(value["file"], serde_json::Value::Null);
+        assert_eq!(value["line"], serde_json::Value::Null);
+        assert_eq!(value["status"], "faulted");
+        assert_eq!(value["fault"], fault.to_string());
+    }
+}
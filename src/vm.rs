@@ -0,0 +1,2600 @@
+use crate::{
+    breakpoints::{Breakpoints, Change, Watchpoint},
+    cancel::CancellationToken,
+    coverage::Coverage,
+    ffi::tvm_ctx,
+    host::{HostContext, HostError, HostFunction},
+    memory::PagedMemory,
+    parser::ParseError,
+    preprocessing::{Builtins, PreprocessingError},
+    profile::Profile,
+    program::{Instruction, Operand, Program},
+    snapshot::{Snapshot, SnapshotError},
+    trace::{Before, Status, TraceFormat, Tracer},
+    Memory, MemoryMut, Opcode, OutOfBounds, Register, SourceMap,
+    DEFAULT_STACK_SIZE,
+};
+use std::{
+    cmp,
+    collections::BTreeMap,
+    error::Error,
+    ffi::CStr,
+    fmt::{self, Debug, Display, Formatter},
+    io::{self, Error as IoError, ErrorKind, Read, Write},
+    mem,
+    os::raw::{c_char, c_int},
+    path::Path,
+    ptr,
+    sync::Arc,
+    time::Instant,
+};
+
+/// A TinyVM virtual machine.
+pub struct Vm {
+    /// The loaded program, which may be shared with other VMs.
+    program: Arc<Program>,
+    cpu: Cpu,
+    devices: Devices,
+    cancellation: CancellationToken,
+    breakpoints: Breakpoints,
+    /// The breakpoint we stopped at, which shouldn't trigger again when the
+    /// run is resumed.
+    stopped_at_breakpoint: Option<usize>,
+    /// Set while a run is in progress (e.g. between steps or after running
+    /// out of fuel), so the next run picks up where it left off instead of
+    /// starting from the entry point.
+    suspended: bool,
+    tracer: Option<Tracer>,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+}
+
+impl Vm {
+    /// Create a new VM with [`crate::DEFAULT_MEMORY_SIZE`] bytes of memory.
+    pub fn new() -> Vm {
+        VmBuilder::new()
+            .build()
+            .expect("The default configuration is always valid")
+    }
+
+    /// Read, preprocess, and parse the program at `path` so it is ready to
+    /// be [`Vm::run()`].
+    ///
+    /// Any breakpoints and watchpoints are cleared.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LoadError> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path).map_err(LoadError::Io)?;
+        let builtins = Builtins {
+            file: Some(path.display().to_string()),
+            memory_size: self.memory_size(),
+        };
+
+        let program = Program::parse(src, &builtins)?;
+        self.load_program(Arc::new(program));
+
+        Ok(())
+    }
+
+    /// Use a program which has already been parsed, so it is ready to be
+    /// [`Vm::run()`]. The program can be shared with other VMs.
+    ///
+    /// Any breakpoints and watchpoints are cleared, but the registers and
+    /// memory are left as they are. Use [`Vm::reset()`] to clear them.
+    pub fn load_program(&mut self, program: Arc<Program>) {
+        self.program = program;
+        self.suspended = false;
+        self.breakpoints.clear();
+        self.stopped_at_breakpoint = None;
+        if self.profile.is_some() {
+            self.profile = Some(Profile::new(&self.program));
+        }
+        if self.coverage.is_some() {
+            self.coverage = Some(Coverage::new(&self.program));
+        }
+    }
+
+    /// The program the VM is running.
+    pub fn program(&self) -> &Arc<Program> { &self.program }
+
+    /// Put the registers and memory back to how they were when the VM was
+    /// created, so the loaded program can be run again from the start
+    /// without reparsing it.
+    ///
+    /// Breakpoints, host functions, I/O, profiling, and coverage are all
+    /// left alone, so profiles and coverage accumulate across resets. The
+    /// cancellation token is also kept, so a cancelled VM needs a new one
+    /// from [`Vm::set_cancellation_token()`] before it can run again.
+    pub fn reset(&mut self) {
+        self.cpu = Cpu::new(self.memory_size());
+        self.suspended = false;
+        self.stopped_at_breakpoint = None;
+        self.breakpoints
+            .refresh(&self.cpu.registers, Memory::new(&self.cpu.memory));
+    }
+
+    /// Execute the loaded program, starting from its `start` label, until it
+    /// halts.
+    ///
+    /// Anything printed by the program is written to the output configured
+    /// with [`VmBuilder::output()`], and input is read from
+    /// [`VmBuilder::input()`].
+    ///
+    /// If a previous run was stopped early (e.g. it ran out of fuel or was
+    /// cancelled), this resumes that run instead of starting again. The run
+    /// may still be stopped early using the VM's [`CancellationToken`].
+    pub fn run(&mut self) -> Result<RunOutcome, RunError> {
+        self.run_with_limits(Limits::default())
+    }
+
+    /// Execute at most `fuel` instructions.
+    ///
+    /// When this returns [`RunOutcome::OutOfFuel`] the VM is left exactly
+    /// where it stopped, and the next call to [`Vm::run()`] or
+    /// [`Vm::run_with_fuel()`] will continue from there. This makes it safe
+    /// to run untrusted programs which may never halt.
+    ///
+    /// ```rust
+    /// # use std::io::Write;
+    /// use tinyvm::{RunOutcome, Vm};
+    ///
+    /// # let mut file = tempfile::NamedTempFile::new().unwrap();
+    /// # writeln!(file, "loop: jmp loop").unwrap();
+    /// # let path = file.path();
+    /// let mut vm = Vm::new();
+    /// vm.load(path).unwrap();
+    ///
+    /// let outcome = vm.run_with_fuel(1000).unwrap();
+    ///
+    /// assert_eq!(outcome, RunOutcome::OutOfFuel);
+    /// ```
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<RunOutcome, RunError> {
+        self.run_with_limits(Limits {
+            fuel: Some(fuel),
+            ..Limits::default()
+        })
+    }
+
+    /// Execute the program until it halts or `deadline` passes.
+    ///
+    /// The clock (and the VM's [`CancellationToken`]) is only checked every
+    /// few hundred instructions, so the VM may run slightly past the
+    /// deadline. When this returns [`RunOutcome::DeadlineExceeded`] the VM's
+    /// state can be inspected and the run resumed, just like
+    /// [`Vm::run_with_fuel()`].
+    pub fn run_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<RunOutcome, RunError> {
+        self.run_with_limits(Limits {
+            deadline: Some(deadline),
+            ..Limits::default()
+        })
+    }
+
+    /// Get a handle which can be used to stop this VM from another thread.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Stop the VM when `token` is cancelled instead of using its current
+    /// token (e.g. to run it again after it was cancelled).
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = token;
+    }
+
+    /// Execute exactly one instruction.
+    ///
+    /// The first step starts from the program's entry point, and each
+    /// subsequent step continues from where the last one left off. Once the
+    /// program has halted or faulted, the next step starts it again.
+    ///
+    /// If the instruction is an `inp` and the VM's input reports
+    /// [`ErrorKind::WouldBlock`], nothing is executed and
+    /// [`StepStatus::WaitingForInput`] is returned so the step can be retried
+    /// when more input is available.
+    pub fn step(&mut self) -> StepStatus {
+        self.begin();
+        self.stopped_at_breakpoint = None;
+
+        if self.is_halted() {
+            self.suspended = false;
+            return StepStatus::Halted;
+        }
+
+        self.execute_next()
+    }
+
+    /// Keep calling [`Vm::step()`] until the program stops making progress,
+    /// returning the final status (i.e. anything other than
+    /// [`StepStatus::Continued`]).
+    pub fn run_until_halt(&mut self) -> StepStatus {
+        loop {
+            match self.step() {
+                StepStatus::Continued => continue,
+                other => return other,
+            }
+        }
+    }
+
+    fn run_with_limits(
+        &mut self,
+        limits: Limits,
+    ) -> Result<RunOutcome, RunError> {
+        self.begin();
+        // another value changed at the same time as the last watchpoint
+        if let Some(change) = self.breakpoints.next_pending() {
+            return Ok(RunOutcome::watchpoint(change));
+        }
+        // only forget the breakpoint once we've actually moved past it, so
+        // stopping early (e.g. with no fuel) resumes from it again
+        let resuming_from = self.stopped_at_breakpoint;
+        self.breakpoints
+            .refresh(&self.cpu.registers, Memory::new(&self.cpu.memory));
+        let mut instructions_executed = 0;
+
+        loop {
+            if self.is_halted() {
+                self.suspended = false;
+                return Ok(RunOutcome::Halted);
+            }
+
+            if let Some(outcome) =
+                limits.check(instructions_executed, &self.cancellation)
+            {
+                return Ok(outcome);
+            }
+
+            let eip = self.cpu.eip();
+            let resuming =
+                instructions_executed == 0 && resuming_from == Some(eip);
+            if !resuming && self.breakpoints.contains(eip) {
+                self.stopped_at_breakpoint = Some(eip);
+                return Ok(RunOutcome::Breakpoint { eip });
+            }
+
+            let status = self.execute_next();
+            if !matches!(status, StepStatus::WaitingForInput) {
+                self.stopped_at_breakpoint = None;
+            }
+
+            if let StepStatus::Continued | StepStatus::Halted = status {
+                if let Some(change) = self.breakpoints.changes(
+                    eip,
+                    &self.cpu.registers,
+                    Memory::new(&self.cpu.memory),
+                ) {
+                    // make sure resuming after the last instruction halts
+                    // instead of restarting the program
+                    self.suspended = true;
+                    return Ok(RunOutcome::watchpoint(change));
+                }
+            }
+
+            match status {
+                StepStatus::Continued => instructions_executed += 1,
+                StepStatus::Halted => return Ok(RunOutcome::Halted),
+                StepStatus::WaitingForInput => {
+                    return Ok(RunOutcome::WaitingForInput)
+                },
+                StepStatus::Faulted(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Stop before executing the instruction at index `instruction`,
+    /// returning `false` if there was already a breakpoint there.
+    ///
+    /// When a breakpoint is hit, [`Vm::run()`] returns
+    /// [`RunOutcome::Breakpoint`] and the next run resumes from that
+    /// instruction.
+    pub fn add_breakpoint(&mut self, instruction: usize) -> bool {
+        self.breakpoints.add(instruction)
+    }
+
+    /// Stop before executing the instruction a label refers to, returning
+    /// that instruction's index, or `None` if there is no such label.
+    pub fn add_breakpoint_at_label(&mut self, label: &str) -> Option<usize> {
+        let instruction = self.label(label)?;
+        self.breakpoints.add(instruction);
+        Some(instruction)
+    }
+
+    pub fn remove_breakpoint(&mut self, instruction: usize) -> bool {
+        self.breakpoints.remove(instruction)
+    }
+
+    /// The indices of every instruction with a breakpoint, in order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter()
+    }
+
+    /// Stop whenever a register or memory location changes, returning
+    /// `false` if the memory location is out of bounds.
+    ///
+    /// The check happens after each instruction, so when
+    /// [`RunOutcome::Watchpoint`] is returned, `eip` already points at the
+    /// next instruction. If an instruction changes several watched values,
+    /// each following run reports the next one before executing anything.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        self.breakpoints.watch(
+            watchpoint,
+            &self.cpu.registers,
+            Memory::new(&self.cpu.memory),
+        )
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        self.breakpoints.unwatch(watchpoint)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = Watchpoint> + '_ {
+        self.breakpoints.watchpoints()
+    }
+
+    /// Remove all breakpoints and watchpoints.
+    pub fn clear_breakpoints(&mut self) { self.breakpoints.clear(); }
+
+    /// The instructions in the loaded program.
+    pub fn instructions(&self) -> &[Instruction] { &self.program.instructions }
+
+    /// Maps the [`Instruction::line`] of each instruction back to the file
+    /// and line it was loaded from.
+    pub fn source_map(&self) -> &SourceMap { self.program.source_map() }
+
+    /// Look up the index of the instruction a label refers to.
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.program.label(name)
+    }
+
+    /// Start a new run, unless we are resuming one which was stopped early.
+    fn begin(&mut self) {
+        if !self.suspended {
+            *self.cpu.reg(Register::Eip) = self.program.start as i32;
+            self.suspended = true;
+
+            if let Some(profile) = &mut self.profile {
+                profile.begin(self.program.start);
+            }
+        }
+    }
+
+    fn is_halted(&self) -> bool { self.program.get(self.cpu.eip()).is_none() }
+
+    /// Execute the instruction at `eip`, which must exist.
+    fn execute_next(&mut self) -> StepStatus {
+        let eip = self.cpu.eip();
+        let instruction = &self.program.instructions[eip];
+        let before = match self.tracer {
+            Some(_) => Some(self.cpu.before(instruction)),
+            None => None,
+        };
+
+        let result = self.cpu.execute(
+            instruction,
+            self.program.len(),
+            &mut self.devices,
+        );
+
+        if let (Ok(Executed::Completed), Some(profile)) =
+            (&result, &mut self.profile)
+        {
+            profile.record(eip, instruction.opcode, self.cpu.eip());
+        }
+
+        if let (Ok(Executed::Completed), Some(coverage)) =
+            (&result, &mut self.coverage)
+        {
+            coverage.record(eip, self.cpu.eip());
+        }
+
+        if let (Some(tracer), Some(before)) = (&mut self.tracer, before) {
+            let status = match &result {
+                Ok(Executed::Completed) => Status::Completed,
+                Ok(Executed::WaitingForInput) => Status::WaitingForInput,
+                Err(fault) => Status::Faulted(fault),
+            };
+            tracer.record(
+                eip,
+                instruction,
+                self.program.source_map().lookup(instruction.line),
+                &before,
+                &self.cpu.registers,
+                status,
+            );
+        }
+
+        match result {
+            Ok(Executed::Completed) if self.is_halted() => {
+                self.suspended = false;
+                StepStatus::Halted
+            },
+            Ok(Executed::Completed) => StepStatus::Continued,
+            Ok(Executed::WaitingForInput) => StepStatus::WaitingForInput,
+            Err(fault) => {
+                self.suspended = false;
+                StepStatus::Faulted(RunError {
+                    eip,
+                    instruction: instruction.to_string(),
+                    line: instruction.line,
+                    fault,
+                })
+            },
+        }
+    }
+
+    /// Write a record of every instruction executed from now on to `writer`,
+    /// including instructions which fault.
+    ///
+    /// This replaces any trace set up previously. If writing to the trace
+    /// fails, the program keeps running but nothing more is traced, and the
+    /// error is reported by [`Vm::trace_error()`] and [`Vm::stop_tracing()`].
+    pub fn trace_to<W: Write + Send + 'static>(
+        &mut self,
+        writer: W,
+        format: TraceFormat,
+    ) {
+        self.tracer = Some(Tracer::new(Box::new(writer), format));
+    }
+
+    /// Stop writing an execution trace, flushing the writer and returning
+    /// the error which stopped the trace early (if any).
+    pub fn stop_tracing(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// The error which stopped the execution trace from being written.
+    pub fn trace_error(&self) -> Option<&IoError> {
+        self.tracer.as_ref().and_then(Tracer::error)
+    }
+
+    /// Start counting how often each instruction is executed, discarding
+    /// any previous [`Profile`].
+    ///
+    /// The profile is reset whenever a new program is loaded.
+    pub fn start_profiling(&mut self) {
+        let mut profile = Profile::new(&self.program);
+        profile.begin(self.program.start);
+        self.profile = Some(profile);
+    }
+
+    /// Stop profiling, returning the counts gathered so far.
+    pub fn stop_profiling(&mut self) -> Option<Profile> { self.profile.take() }
+
+    /// The counts gathered since [`Vm::start_profiling()`] was called.
+    pub fn profile(&self) -> Option<&Profile> { self.profile.as_ref() }
+
+    /// Create a copy of this VM which shares its program and memory.
+    ///
+    /// Memory is copied one page at a time the first time either VM writes
+    /// to it, so forking a VM after an expensive setup phase is cheap.
+    /// Registers, breakpoints, and any run in progress are copied too, so
+    /// the fork picks up exactly where this VM is.
+    ///
+    /// Host functions, profiling, and coverage aren't copied, and the fork
+    /// gets its own [`CancellationToken`]. Output is captured if this VM's
+    /// output is captured, otherwise it goes to stdout. Use
+    /// [`Vm::fork_with()`] to configure the fork's I/O.
+    ///
+    /// ```rust
+    /// # use std::io::Write;
+    /// use tinyvm::{Register, Vm, VmBuilder};
+    ///
+    /// # let mut file = tempfile::NamedTempFile::new().unwrap();
+    /// # writeln!(file, "start: mov [1], 42\n add eax, [1]").unwrap();
+    /// # let path = file.path();
+    /// let mut setup = Vm::new();
+    /// setup.load(path).unwrap();
+    ///
+    /// let mut variations: Vec<Vm> = (0..3)
+    ///     .map(|i| {
+    ///         let mut vm = setup.fork();
+    ///         vm.set_reg(Register::Eax, i);
+    ///         vm
+    ///     })
+    ///     .collect();
+    ///
+    /// for (i, vm) in variations.iter_mut().enumerate() {
+    ///     vm.run().unwrap();
+    ///     assert_eq!(vm.reg(Register::Eax), 42 + i as i32);
+    /// }
+    /// ```
+    pub fn fork(&self) -> Vm {
+        let builder = match self.devices.output {
+            Output::Captured(_) => VmBuilder::new().capture_output(),
+            _ => VmBuilder::new(),
+        };
+
+        self.fork_with(builder)
+    }
+
+    /// Create a copy of this VM (see [`Vm::fork()`]) which uses the output,
+    /// input, cancellation token, and trace from `builder`.
+    ///
+    /// The builder's memory size is ignored, because the fork always has the
+    /// same memory as this VM.
+    pub fn fork_with(&self, builder: VmBuilder) -> Vm {
+        Vm {
+            program: Arc::clone(&self.program),
+            cpu: self.cpu.clone(),
+            devices: Devices {
+                output: builder.output,
+                input: builder.input,
+                host_calls: BTreeMap::new(),
+            },
+            cancellation: builder.cancellation,
+            breakpoints: self.breakpoints.clone(),
+            stopped_at_breakpoint: self.stopped_at_breakpoint,
+            suspended: self.suspended,
+            tracer: builder.tracer,
+            profile: None,
+            coverage: None,
+        }
+    }
+
+    /// Capture the VM's registers, flags, and memory so execution can be
+    /// resumed later with [`Vm::restore()`], possibly in another process.
+    ///
+    /// ```rust
+    /// # use std::io::Write;
+    /// use tinyvm::{Register, RunOutcome, Snapshot, Vm};
+    ///
+    /// # let mut file = tempfile::NamedTempFile::new().unwrap();
+    /// # writeln!(file, "start: inc eax\n cmp eax, 100\n jne start").unwrap();
+    /// # let path = file.path();
+    /// let mut vm = Vm::new();
+    /// vm.load(path).unwrap();
+    /// assert_eq!(vm.run_with_fuel(10).unwrap(), RunOutcome::OutOfFuel);
+    /// let bytes = vm.snapshot().to_bytes();
+    ///
+    /// let mut resumed = Vm::new();
+    /// resumed.load(path).unwrap();
+    /// resumed.restore(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+    /// resumed.run().unwrap();
+    ///
+    /// assert_eq!(resumed.reg(Register::Eax), 100);
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program: self.program.fingerprint(),
+            registers: self.cpu.registers,
+            flags: self.cpu.flags,
+            remainder: self.cpu.remainder,
+            stack_top: self.cpu.stack_top,
+            suspended: self.suspended,
+            memory: self.cpu.memory.clone(),
+        }
+    }
+
+    /// Restore the state captured by [`Vm::snapshot()`].
+    ///
+    /// The program the snapshot was taken from must already be loaded. If it
+    /// was taken part way through a run, the next call to [`Vm::run()`] (or
+    /// [`Vm::step()`]) continues from where it left off. The VM's memory is
+    /// resized to match the snapshot.
+    pub fn restore(
+        &mut self,
+        snapshot: &Snapshot,
+    ) -> Result<(), SnapshotError> {
+        if snapshot.program != self.program.fingerprint() {
+            return Err(SnapshotError::ProgramMismatch);
+        }
+
+        self.cpu.registers = snapshot.registers;
+        self.cpu.flags = snapshot.flags;
+        self.cpu.remainder = snapshot.remainder;
+        self.cpu.stack_top = snapshot.stack_top;
+        self.cpu.memory.clone_from(&snapshot.memory);
+        self.suspended = snapshot.suspended;
+        self.stopped_at_breakpoint = None;
+        self.breakpoints
+            .refresh(&self.cpu.registers, Memory::new(&self.cpu.memory));
+
+        Ok(())
+    }
+
+    /// Start recording which instructions and branches are executed,
+    /// discarding any previous [`Coverage`].
+    ///
+    /// Coverage accumulates across runs, and is reset whenever a new program
+    /// is loaded.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(&self.program));
+    }
+
+    /// Stop recording coverage, returning everything recorded so far.
+    pub fn stop_coverage(&mut self) -> Option<Coverage> { self.coverage.take() }
+
+    /// The coverage recorded since [`Vm::start_coverage()`] was called.
+    pub fn coverage(&self) -> Option<&Coverage> { self.coverage.as_ref() }
+
+    /// Register a function which the program can call with `int <number>`,
+    /// returning the function previously registered under that number (if
+    /// any).
+    ///
+    /// The function can inspect and modify the VM's registers and memory
+    /// through the [`HostContext`], and returning an error will abort the
+    /// program with [`VmFault::Host`]. Setting `eip` makes the program jump
+    /// to that instruction instead of continuing after the `int`.
+    ///
+    /// If the function returns an error, or sets `eip` to somewhere outside
+    /// the program, `eip` is left pointing at the `int`. Any other registers
+    /// or memory the function wrote before failing keep their new values.
+    ///
+    /// ```rust
+    /// use tinyvm::{Register, Vm};
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.register_host_call(1, |ctx| {
+    ///     let eax = ctx.reg(Register::Eax);
+    ///     let doubled = eax.checked_mul(2).ok_or("Overflow")?;
+    ///     ctx.set_reg(Register::Eax, doubled);
+    ///     Ok(())
+    /// });
+    /// ```
+    pub fn register_host_call<F>(
+        &mut self,
+        number: i32,
+        function: F,
+    ) -> Option<HostFunction>
+    where
+        F: FnMut(&mut HostContext<'_>) -> Result<(), HostError>
+            + Send
+            + 'static,
+    {
+        self.devices.host_calls.insert(number, Box::new(function))
+    }
+
+    /// Remove the host function registered under `number`.
+    pub fn remove_host_call(&mut self, number: i32) -> Option<HostFunction> {
+        self.devices.host_calls.remove(&number)
+    }
+
+    /// Get everything the program has printed so far, if the VM was created
+    /// with [`VmBuilder::capture_output()`].
+    pub fn captured_output(&self) -> Option<&[u8]> {
+        match self.devices.output {
+            Output::Captured(ref buffer) => Some(buffer),
+            _ => None,
+        }
+    }
+
+    /// Take everything the program has printed so far, if the VM was created
+    /// with [`VmBuilder::capture_output()`], leaving the buffer empty.
+    pub fn take_captured_output(&mut self) -> Option<Vec<u8>> {
+        match self.devices.output {
+            Output::Captured(ref mut buffer) => Some(mem::take(buffer)),
+            _ => None,
+        }
+    }
+
+    /// The index of the instruction the next [`Vm::step()`] or [`Vm::run()`]
+    /// will execute.
+    ///
+    /// Unlike `eip`, this accounts for a new run starting from the `start`
+    /// label.
+    pub fn next_eip(&self) -> i32 {
+        if self.suspended {
+            self.reg(Register::Eip)
+        } else {
+            self.program.start as i32
+        }
+    }
+
+    /// Read the contents of a register.
+    ///
+    /// `esp` and `ebp` hold byte offsets into the VM's memory.
+    pub fn reg(&self, register: Register) -> i32 {
+        self.cpu.registers[register as usize]
+    }
+
+    /// Overwrite the contents of a register.
+    ///
+    /// Values written to `esp` and `ebp` are byte offsets into the VM's
+    /// memory. Note that [`Vm::run()`] resets `eip` to the program's entry
+    /// point unless it is resuming a run which ran out of fuel.
+    pub fn set_reg(&mut self, register: Register, value: i32) {
+        self.cpu.registers[register as usize] = value;
+    }
+
+    /// The byte address of the top of the stack (i.e. the initial value of
+    /// `esp`). The stack grows down from here.
+    pub fn stack_top(&self) -> usize { self.cpu.stack_top as usize }
+
+    /// The number of bytes of memory available to the program.
+    pub fn memory_size(&self) -> usize { self.cpu.memory.len() }
+
+    /// Get a read-only view of the VM's memory.
+    pub fn memory(&self) -> Memory<'_> { Memory::new(&self.cpu.memory) }
+
+    /// Get a mutable view of the VM's memory, for seeding input data.
+    pub fn memory_mut(&mut self) -> MemoryMut<'_> {
+        MemoryMut::new(&mut self.cpu.memory)
+    }
+}
+
+impl Debug for Vm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vm")
+            .field("program", &self.program)
+            .field("cpu", &self.cpu)
+            .field("devices", &self.devices)
+            .field("cancellation", &self.cancellation)
+            .field("breakpoints", &self.breakpoints)
+            .field("stopped_at_breakpoint", &self.stopped_at_breakpoint)
+            .field("suspended", &self.suspended)
+            .field("tracer", &self.tracer)
+            .field("profile", &self.profile)
+            .field("coverage", &self.coverage)
+            .finish()
+    }
+}
+
+/// A builder for configuring a [`Vm`] before it is created.
+#[derive(Debug)]
+pub struct VmBuilder {
+    memory_size: usize,
+    output: Output,
+    input: Input,
+    cancellation: CancellationToken,
+    tracer: Option<Tracer>,
+}
+
+impl VmBuilder {
+    pub fn new() -> VmBuilder {
+        VmBuilder {
+            memory_size: crate::DEFAULT_MEMORY_SIZE,
+            output: Output::Stdout,
+            input: Input::Stdin,
+            cancellation: CancellationToken::new(),
+            tracer: None,
+        }
+    }
+
+    /// Set the number of bytes of memory to allocate for the VM.
+    ///
+    /// The bottom of memory is used for the stack, which grows down from
+    /// [`DEFAULT_STACK_SIZE`] (or the top of memory, if that is smaller).
+    pub fn memory_size(mut self, bytes: usize) -> VmBuilder {
+        self.memory_size = bytes;
+        self
+    }
+
+    /// Send anything the program prints (e.g. with `prn`) to `writer`
+    /// instead of stdout.
+    pub fn output<W: Write + Send + 'static>(mut self, writer: W) -> VmBuilder {
+        self.output = Output::Writer(Box::new(writer));
+        self
+    }
+
+    /// Save anything the program prints in an in-memory buffer, which can be
+    /// retrieved with [`Vm::captured_output()`].
+    pub fn capture_output(mut self) -> VmBuilder {
+        self.output = Output::Captured(Vec::new());
+        self
+    }
+
+    /// Read the bytes for the `inp` instruction from `reader` instead of
+    /// stdin.
+    pub fn input<R: Read + Send + 'static>(mut self, reader: R) -> VmBuilder {
+        self.input = Input::Reader(Box::new(reader));
+        self
+    }
+
+    /// Stop the VM when `token` is cancelled. This lets a single token
+    /// cancel several VMs at once.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> VmBuilder {
+        self.cancellation = token;
+        self
+    }
+
+    /// Write a record of every instruction the VM executes to `writer`.
+    ///
+    /// See [`Vm::trace_to()`].
+    pub fn trace_to<W: Write + Send + 'static>(
+        mut self,
+        writer: W,
+        format: TraceFormat,
+    ) -> VmBuilder {
+        self.tracer = Some(Tracer::new(Box::new(writer), format));
+        self
+    }
+
+    /// Create the [`Vm`].
+    ///
+    /// This fails if the memory size doesn't fit in an `i32`, because the
+    /// stack pointer couldn't address all of it.
+    pub fn build(self) -> Result<Vm, BuildError> {
+        if self.memory_size > i32::MAX as usize {
+            return Err(BuildError::MemoryTooLarge {
+                requested: self.memory_size,
+            });
+        }
+
+        Ok(Vm {
+            program: Arc::default(),
+            cpu: Cpu::new(self.memory_size),
+            devices: Devices {
+                output: self.output,
+                input: self.input,
+                host_calls: BTreeMap::new(),
+            },
+            cancellation: self.cancellation,
+            breakpoints: Breakpoints::default(),
+            stopped_at_breakpoint: None,
+            suspended: false,
+            tracer: self.tracer,
+            profile: None,
+            coverage: None,
+        })
+    }
+}
+
+impl Default for VmBuilder {
+    fn default() -> VmBuilder { VmBuilder::new() }
+}
+
+impl Default for Vm {
+    fn default() -> Vm { Vm::new() }
+}
+
+/// The reasons a [`VmBuilder`] may be unable to create a [`Vm`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The requested memory is bigger than the VM can address.
+    MemoryTooLarge { requested: usize },
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MemoryTooLarge { requested } => write!(
+                f,
+                "Unable to use {} bytes of memory, the VM can't address more than {}",
+                requested,
+                i32::MAX
+            ),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// The VM's registers and memory (the equivalent of `tvm_mem`).
+#[derive(Clone, PartialEq)]
+struct Cpu {
+    registers: [i32; Register::ALL.len()],
+    flags: i32,
+    remainder: i32,
+    memory: PagedMemory,
+    /// The initial value of `esp`, used to detect stack underflows.
+    stack_top: i32,
+}
+
+impl Cpu {
+    fn new(memory_size: usize) -> Cpu {
+        // the stack grows down, starting from the top of the stack region
+        let stack_size = cmp::min(DEFAULT_STACK_SIZE, memory_size);
+        let stack_top = (stack_size - stack_size % 4) as i32;
+
+        let mut registers = [0; Register::ALL.len()];
+        registers[Register::Esp as usize] = stack_top;
+        registers[Register::Ebp as usize] = stack_top;
+
+        Cpu {
+            registers,
+            flags: 0,
+            remainder: 0,
+            memory: PagedMemory::new(memory_size),
+            stack_top,
+        }
+    }
+
+    fn reg(&mut self, register: Register) -> &mut i32 {
+        &mut self.registers[register as usize]
+    }
+
+    /// The index of the next instruction, or [`usize::MAX`] if `eip` is
+    /// negative.
+    fn eip(&self) -> usize {
+        let eip = self.registers[Register::Eip as usize];
+        if eip < 0 {
+            usize::MAX
+        } else {
+            eip as usize
+        }
+    }
+
+    /// Execute a single instruction (the `tvm_step()` function from
+    /// `tvm.h`), updating `eip` to point at the next one.
+    ///
+    /// If the instruction faults, `eip` is left pointing at it.
+    fn execute(
+        &mut self,
+        instruction: &Instruction,
+        num_instructions: usize,
+        devices: &mut Devices,
+    ) -> Result<Executed, VmFault> {
+        let operands = &instruction.operands;
+        let arg = |i: usize| operands[i];
+        let eip = self.registers[Register::Eip as usize];
+        let mut next = eip.wrapping_add(1);
+
+        match instruction.opcode {
+            Opcode::Nop => {},
+            Opcode::Int => {
+                let number = self.read(arg(0))?;
+                let function = devices
+                    .host_calls
+                    .get_mut(&number)
+                    .ok_or(VmFault::UnknownHostCall { number })?;
+                let mut ctx =
+                    HostContext::new(&mut self.registers, &mut self.memory);
+
+                let result = function(&mut ctx);
+
+                // a host which changed eip wants to jump somewhere, so treat
+                // the new value like a jmp target. Putting eip back first
+                // means a failed call or a bad target leaves it on the int.
+                let target = mem::replace(
+                    &mut self.registers[Register::Eip as usize],
+                    eip,
+                );
+                result.map_err(|error| VmFault::Host { number, error })?;
+                if target != eip {
+                    next = target;
+                }
+            },
+            Opcode::Mov => {
+                let value = self.read(arg(1))?;
+                self.write(arg(0), value)?;
+            },
+            Opcode::Push => {
+                let value = self.read(arg(0))?;
+                self.push(value)?;
+            },
+            Opcode::Pop => {
+                // check everything before touching esp, so a fault leaves
+                // the stack as it was
+                let value = self.peek()?;
+                self.check_writable(arg(0))?;
+                self.pop()?;
+                self.write(arg(0), value)?;
+            },
+            Opcode::Pushf => self.push(self.flags)?,
+            Opcode::Popf => self.flags = self.pop()?,
+            Opcode::Inc => self.update(arg(0), 1, i32::wrapping_add)?,
+            Opcode::Dec => self.update(arg(0), 1, i32::wrapping_sub)?,
+            Opcode::Add => self.binary_op(operands, i32::wrapping_add)?,
+            Opcode::Sub => self.binary_op(operands, i32::wrapping_sub)?,
+            Opcode::Mul => self.binary_op(operands, i32::wrapping_mul)?,
+            Opcode::Div => {
+                let divisor = self.read(arg(1))?;
+                if divisor == 0 {
+                    return Err(VmFault::DivideByZero);
+                }
+                self.update(arg(0), divisor, i32::wrapping_div)?;
+            },
+            Opcode::Mod => {
+                let divisor = self.read(arg(1))?;
+                if divisor == 0 {
+                    return Err(VmFault::DivideByZero);
+                }
+                self.remainder = self.read(arg(0))?.wrapping_rem(divisor);
+            },
+            Opcode::Rem => self.write(arg(0), self.remainder)?,
+            Opcode::Not => self.update(arg(0), 0, |a, _| !a)?,
+            Opcode::Xor => self.binary_op(operands, |a, b| a ^ b)?,
+            Opcode::Or => self.binary_op(operands, |a, b| a | b)?,
+            Opcode::And => self.binary_op(operands, |a, b| a & b)?,
+            Opcode::Shl => {
+                self.binary_op(operands, |a, b| a.wrapping_shl(b as u32))?
+            },
+            Opcode::Shr => {
+                self.binary_op(operands, |a, b| a.wrapping_shr(b as u32))?
+            },
+            Opcode::Cmp => {
+                let (a, b) = (self.read(arg(0))?, self.read(arg(1))?);
+                self.flags = (a == b) as i32 | ((a > b) as i32) << 1;
+            },
+            Opcode::Jmp => next = self.read(arg(0))?,
+            Opcode::Call => {
+                next = self.read(arg(0))?;
+                check_jump(next, num_instructions)?;
+                self.push(eip)?;
+            },
+            Opcode::Ret => {
+                next = self.peek()?.wrapping_add(1);
+                check_jump(next, num_instructions)?;
+                self.pop()?;
+            },
+            Opcode::Je => {
+                self.jump_if(self.flags & 0x1 != 0, arg(0), &mut next)?
+            },
+            Opcode::Jne => {
+                self.jump_if(self.flags & 0x1 == 0, arg(0), &mut next)?
+            },
+            Opcode::Jg => {
+                self.jump_if(self.flags & 0x2 != 0, arg(0), &mut next)?
+            },
+            Opcode::Jge => {
+                self.jump_if(self.flags & 0x3 != 0, arg(0), &mut next)?
+            },
+            Opcode::Jl => {
+                self.jump_if(self.flags & 0x3 == 0, arg(0), &mut next)?
+            },
+            Opcode::Jle => {
+                self.jump_if(self.flags & 0x2 == 0, arg(0), &mut next)?
+            },
+            Opcode::Prn => writeln!(devices.output, "{}", self.read(arg(0))?)
+                .map_err(VmFault::Io)?,
+            Opcode::Inp => {
+                // don't consume any input if it can't be stored
+                self.check_writable(arg(0))?;
+                let value = match devices.input.read_byte() {
+                    Ok(value) => value,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        return Ok(Executed::WaitingForInput);
+                    },
+                    Err(e) => return Err(VmFault::Io(e)),
+                };
+                self.write(arg(0), value)?;
+            },
+        }
+
+        check_jump(next, num_instructions)?;
+        *self.reg(Register::Eip) = next;
+
+        Ok(Executed::Completed)
+    }
+
+    /// Remember the state needed to trace `instruction`.
+    fn before(&self, instruction: &Instruction) -> Before {
+        Before {
+            registers: self.registers,
+            operands: instruction
+                .operands
+                .iter()
+                .map(|&operand| self.read(operand).ok())
+                .collect(),
+        }
+    }
+
+    fn read(&self, operand: Operand) -> Result<i32, VmFault> {
+        match operand {
+            Operand::Register(reg) => Ok(self.registers[reg as usize]),
+            Operand::Value(value) => Ok(value),
+            Operand::Memory(index) => Memory::new(&self.memory)
+                .read_i32(memory_address(index))
+                .map_err(VmFault::InvalidAddress),
+        }
+    }
+
+    fn write(&mut self, operand: Operand, value: i32) -> Result<(), VmFault> {
+        match operand {
+            Operand::Register(reg) => *self.reg(reg) = value,
+            // like libtvm, writing to a constant does nothing useful
+            Operand::Value(_) => {},
+            Operand::Memory(index) => MemoryMut::new(&mut self.memory)
+                .write_i32(memory_address(index), value)
+                .map_err(VmFault::InvalidAddress)?,
+        }
+
+        Ok(())
+    }
+
+    fn update<F>(
+        &mut self,
+        operand: Operand,
+        rhs: i32,
+        op: F,
+    ) -> Result<(), VmFault>
+    where
+        F: FnOnce(i32, i32) -> i32,
+    {
+        let value = op(self.read(operand)?, rhs);
+        self.write(operand, value)
+    }
+
+    fn binary_op<F>(
+        &mut self,
+        operands: &[Operand],
+        op: F,
+    ) -> Result<(), VmFault>
+    where
+        F: FnOnce(i32, i32) -> i32,
+    {
+        let rhs = self.read(operands[1])?;
+        self.update(operands[0], rhs, op)
+    }
+
+    fn jump_if(
+        &self,
+        condition: bool,
+        target: Operand,
+        next: &mut i32,
+    ) -> Result<(), VmFault> {
+        if condition {
+            *next = self.read(target)?;
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, value: i32) -> Result<(), VmFault> {
+        let esp = self.registers[Register::Esp as usize];
+        if esp < 4 {
+            return Err(VmFault::StackOverflow { esp });
+        }
+
+        let new_esp = esp - 4;
+        MemoryMut::new(&mut self.memory)
+            .write_i32(new_esp as usize, value)
+            .map_err(VmFault::InvalidAddress)?;
+        *self.reg(Register::Esp) = new_esp;
+
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i32, VmFault> {
+        let value = self.peek()?;
+        *self.reg(Register::Esp) += 4;
+
+        Ok(value)
+    }
+
+    /// Read the value on top of the stack without popping it.
+    fn peek(&self) -> Result<i32, VmFault> {
+        let esp = self.registers[Register::Esp as usize];
+        if esp < 0 || esp >= self.stack_top {
+            return Err(VmFault::StackUnderflow { esp });
+        }
+
+        Memory::new(&self.memory)
+            .read_i32(esp as usize)
+            .map_err(VmFault::InvalidAddress)
+    }
+
+    /// Make sure writing to `operand` won't fault.
+    fn check_writable(&self, operand: Operand) -> Result<(), VmFault> {
+        self.read(operand).map(|_| ())
+    }
+}
+
+/// The result of successfully executing an instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Executed {
+    Completed,
+    /// The instruction needs input which isn't available yet, so `eip` was
+    /// left unchanged.
+    WaitingForInput,
+}
+
+/// Convert the index from a `[n]` operand into a byte address.
+fn memory_address(index: u32) -> usize { index as usize * 4 }
+
+/// Make sure `target` is a valid instruction index. Jumping to just past the
+/// last instruction is allowed, and halts the program.
+fn check_jump(target: i32, num_instructions: usize) -> Result<(), VmFault> {
+    if target < 0 || target as usize > num_instructions {
+        Err(VmFault::InvalidJump { target })
+    } else {
+        Ok(())
+    }
+}
+
+impl Debug for Cpu {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let registers: Vec<_> = Register::ALL
+            .iter()
+            .map(|&reg| (reg, self.registers[reg as usize]))
+            .collect();
+
+        // memory is far too big to print
+        f.debug_struct("Cpu")
+            .field("registers", &registers)
+            .field("flags", &self.flags)
+            .field("remainder", &self.remainder)
+            .field("memory_size", &self.memory.len())
+            .field("stack_top", &self.stack_top)
+            .finish()
+    }
+}
+
+/// Everything outside the [`Cpu`] that instructions can interact with.
+struct Devices {
+    output: Output,
+    input: Input,
+    host_calls: BTreeMap<i32, HostFunction>,
+}
+
+impl Debug for Devices {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Devices")
+            .field("output", &self.output)
+            .field("input", &self.input)
+            .field("host_calls", &self.host_calls.keys())
+            .finish()
+    }
+}
+
+/// Where the VM should send anything the program prints.
+enum Output {
+    Stdout,
+    Captured(Vec<u8>),
+    Writer(Box<dyn Write + Send>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout => io::stdout().write(buf),
+            Output::Captured(buffer) => buffer.write(buf),
+            Output::Writer(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().flush(),
+            Output::Captured(buffer) => buffer.flush(),
+            Output::Writer(writer) => writer.flush(),
+        }
+    }
+}
+
+impl Debug for Output {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Stdout => f.write_str("Stdout"),
+            Output::Captured(buffer) => f
+                .debug_tuple("Captured")
+                .field(&String::from_utf8_lossy(buffer))
+                .finish(),
+            Output::Writer(_) => f.write_str("Writer"),
+        }
+    }
+}
+
+/// Where the `inp` instruction reads from.
+enum Input {
+    Stdin,
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Input {
+    /// Read a single byte, returning `-1` at end-of-file.
+    fn read_byte(&mut self) -> io::Result<i32> {
+        let mut buffer = [0_u8];
+
+        loop {
+            let result = match self {
+                Input::Stdin => io::stdin().read(&mut buffer),
+                Input::Reader(reader) => reader.read(&mut buffer),
+            };
+
+            match result {
+                Ok(0) => return Ok(-1),
+                Ok(_) => return Ok(i32::from(buffer[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Debug for Input {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Stdin => f.write_str("Stdin"),
+            Input::Reader(_) => f.write_str("Reader"),
+        }
+    }
+}
+
+/// The reasons loading a program may fail.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file couldn't be read.
+    Io(IoError),
+    Preprocessing(PreprocessingError),
+    Parse(ParseError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "Unable to read the program: {}", e),
+            LoadError::Preprocessing(e) => {
+                write!(f, "Preprocessing failed: {}", e)
+            },
+            LoadError::Parse(e) => write!(f, "Parsing failed: {}", e),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Preprocessing(e) => Some(e),
+            LoadError::Parse(e) => Some(e),
+        }
+    }
+}
+
+/// Why a run returned without an error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The program ran to completion.
+    Halted,
+    /// The instruction budget was used up before the program halted.
+    OutOfFuel,
+    /// The deadline passed before the program halted.
+    DeadlineExceeded,
+    /// The VM's [`CancellationToken`] was cancelled.
+    Cancelled,
+    /// An `inp` instruction is waiting for more input (see
+    /// [`StepStatus::WaitingForInput`]).
+    WaitingForInput,
+    /// Stopped before executing an instruction with a breakpoint.
+    Breakpoint {
+        /// The index of the instruction.
+        eip: usize,
+    },
+    /// Stopped because a watched value changed.
+    Watchpoint {
+        watchpoint: Watchpoint,
+        /// The index of the instruction which changed the value.
+        eip: usize,
+        old_value: i32,
+        new_value: i32,
+    },
+}
+
+impl RunOutcome {
+    fn watchpoint(change: Change) -> RunOutcome {
+        RunOutcome::Watchpoint {
+            watchpoint: change.watchpoint,
+            eip: change.eip,
+            old_value: change.old_value,
+            new_value: change.new_value,
+        }
+    }
+}
+
+/// The result of executing a single instruction with [`Vm::step()`].
+#[derive(Debug)]
+pub enum StepStatus {
+    /// The instruction was executed and there are more to go.
+    Continued,
+    /// The program has run to completion.
+    Halted,
+    /// The instruction faulted. The VM's state is left as it was before the
+    /// instruction was executed, apart from anything a host function wrote
+    /// before returning an error.
+    Faulted(RunError),
+    /// The instruction is an `inp`, but reading from the VM's input would
+    /// block. Nothing was executed, so the step can be retried later.
+    WaitingForInput,
+}
+
+/// When a run should be stopped early.
+#[derive(Debug, Default, Copy, Clone)]
+struct Limits {
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl Limits {
+    /// How many instructions to execute between checking the clock and the
+    /// cancellation token, which are much more expensive than counting fuel.
+    const CHECK_INTERVAL: u64 = 256;
+
+    /// Should we stop before executing the next instruction?
+    fn check(
+        &self,
+        instructions_executed: u64,
+        cancellation: &CancellationToken,
+    ) -> Option<RunOutcome> {
+        if self.fuel.is_some_and(|fuel| instructions_executed >= fuel) {
+            return Some(RunOutcome::OutOfFuel);
+        }
+
+        if instructions_executed % Limits::CHECK_INTERVAL != 0 {
+            return None;
+        }
+
+        if cancellation.is_cancelled() {
+            Some(RunOutcome::Cancelled)
+        } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            Some(RunOutcome::DeadlineExceeded)
+        } else {
+            None
+        }
+    }
+}
+
+/// The error returned when a program faults.
+///
+/// The VM's registers and memory are left as they were immediately before
+/// the faulting instruction, so they can be inspected.
+#[derive(Debug)]
+pub struct RunError {
+    /// The index of the instruction which faulted.
+    pub eip: usize,
+    /// The faulting instruction (e.g. `div eax, ebx`).
+    pub instruction: String,
+    /// The line in the preprocessed source the instruction came from.
+    pub line: usize,
+    pub fault: VmFault,
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at instruction {} (\"{}\", line {})",
+            self.fault, self.eip, self.instruction, self.line
+        )
+    }
+}
+
+impl Error for RunError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> { Some(&self.fault) }
+}
+
+/// The reasons a program may stop running early.
+#[derive(Debug)]
+pub enum VmFault {
+    DivideByZero,
+    /// Pushing would move `esp` below the start of memory.
+    StackOverflow {
+        esp: i32,
+    },
+    /// Popping when `esp` is already at the top of the stack.
+    StackUnderflow {
+        esp: i32,
+    },
+    /// A memory operand (or the stack pointer) referred to memory outside
+    /// the VM's address space.
+    InvalidAddress(OutOfBounds),
+    /// Tried to jump to something which isn't an instruction.
+    InvalidJump {
+        target: i32,
+    },
+    /// The program executed `int <number>`, but no host function was
+    /// registered under that number.
+    UnknownHostCall {
+        number: i32,
+    },
+    /// A host function returned an error.
+    Host {
+        number: i32,
+        error: HostError,
+    },
+    /// Reading input or writing output failed.
+    Io(IoError),
+}
+
+impl Display for VmFault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VmFault::DivideByZero => write!(f, "Divide by zero"),
+            VmFault::StackOverflow { esp } => {
+                write!(f, "Stack overflow (esp = {})", esp)
+            },
+            VmFault::StackUnderflow { esp } => {
+                write!(f, "Stack underflow (esp = {})", esp)
+            },
+            VmFault::InvalidAddress(e) => write!(f, "Invalid address: {}", e),
+            VmFault::InvalidJump { target } => {
+                write!(f, "Invalid jump target, {}", target)
+            },
+            VmFault::UnknownHostCall { number } => {
+                write!(f, "No host function registered for int {}", number)
+            },
+            VmFault::Host { number, error } => {
+                write!(f, "Host function {} failed: {}", number, error)
+            },
+            VmFault::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for VmFault {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmFault::InvalidAddress(e) => Some(e),
+            VmFault::Host { error, .. } => Some(&**error),
+            VmFault::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Create a new VM with [`crate::DEFAULT_MEMORY_SIZE`] bytes of memory.
+///
+/// The returned pointer is really a [`Vm`] and should be treated as opaque.
+/// Returns a null pointer if the VM couldn't be allocated.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_vm_create() -> *mut tvm_ctx {
+    crate::catch_panic(ptr::null_mut(), || {
+        Box::into_raw(Box::new(Vm::new())) as *mut tvm_ctx
+    })
+}
+
+/// Free a VM created with [`tvm_vm_create()`]. Passing a null pointer is a
+/// no-op.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_vm_destroy(vm: *mut tvm_ctx) {
+    if vm.is_null() {
+        return;
+    }
+
+    crate::catch_panic((), || drop(Box::from_raw(vm as *mut Vm)))
+}
+
+/// Load the program at `filename` into the VM.
+///
+/// Returns `0` on success, or `1` if either pointer is null or the program
+/// couldn't be read, preprocessed, or parsed.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_vm_interpret(
+    vm: *mut tvm_ctx,
+    filename: *mut c_char,
+) -> c_int {
+    if vm.is_null() || filename.is_null() {
+        return 1;
+    }
+
+    crate::catch_panic(1, || {
+        let vm = &mut *(vm as *mut Vm);
+
+        let filename = match CStr::from_ptr(filename).to_str() {
+            Ok(f) => f,
+            Err(_) => return 1,
+        };
+
+        match vm.load(filename) {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    })
+}
+
+/// Run the program loaded into the VM, printing its output to stdout.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_vm_run(vm: *mut tvm_ctx) {
+    if vm.is_null() {
+        return;
+    }
+
+    crate::catch_panic((), || {
+        // like the original printf() calls, we ignore write errors
+        let _ = (*(vm as *mut Vm)).run();
+    })
+}
+
+/// Reset the VM's registers and memory so the loaded program can be run
+/// again without reparsing it.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_vm_reset(vm: *mut tvm_ctx) {
+    if vm.is_null() {
+        return;
+    }
+
+    crate::catch_panic((), || (*(vm as *mut Vm)).reset())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, thread, time::Duration};
+    use tempfile::NamedTempFile;
+
+    fn load(src: &str) -> Vm { load_with(VmBuilder::new(), src) }
+
+    fn load_with(builder: VmBuilder, src: &str) -> Vm {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(src.as_bytes()).unwrap();
+        let mut vm = builder.build().unwrap();
+
+        vm.load(file.path()).unwrap();
+
+        vm
+    }
+
+    #[test]
+    fn configure_the_memory_size() {
+        let vm = VmBuilder::new().memory_size(1024).build().unwrap();
+
+        assert_eq!(vm.memory_size(), 1024);
+        assert_eq!(vm.memory().len(), 1024);
+        // the stack starts at the top of our (tiny) memory
+        assert_eq!(vm.reg(Register::Esp), 1024);
+    }
+
+    #[test]
+    fn memory_that_cant_be_addressed_is_an_error() {
+        let requested = i32::MAX as usize + 1;
+
+        let err = VmBuilder::new().memory_size(requested).build().unwrap_err();
+
+        assert_eq!(err, BuildError::MemoryTooLarge { requested });
+    }
+
+    #[test]
+    fn memory_size_is_visible_to_the_program() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"start:\n  mov eax, __TVM_MEM_SIZE__\n")
+            .unwrap();
+        let mut vm = VmBuilder::new().memory_size(4096).build().unwrap();
+        vm.load(file.path()).unwrap();
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg(Register::Eax), 4096);
+    }
+
+    #[test]
+    fn pass_arguments_in_and_read_results_out() {
+        let mut vm = load("start:\n  mov eax, ebx\n  add eax, ecx\n");
+        vm.set_reg(Register::Ebx, 40);
+        vm.set_reg(Register::Ecx, 2);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg(Register::Eax), 42);
+    }
+
+    #[test]
+    fn stack_pointer_is_an_offset_into_memory() {
+        let mut vm = load("start:\n  push eax\n");
+        let initial_esp = vm.reg(Register::Esp);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg(Register::Esp), initial_esp - 4);
+    }
+
+    #[test]
+    fn seed_memory_and_read_results() {
+        // memory operands are indices into an array of 32-bit integers
+        let mut vm =
+            load("start:\n  mov eax, [1]\n  add eax, 1\n  mov [2], eax\n");
+        vm.memory_mut().write_i32(4, 41).unwrap();
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.memory().read_i32(8).unwrap(), 42);
+    }
+
+    #[test]
+    fn capture_printed_values() {
+        let mut vm = load_with(
+            VmBuilder::new().capture_output(),
+            "start:\n  mov eax, 40\n  prn eax\n  add eax, 2\n  prn eax\n",
+        );
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.captured_output().unwrap(), b"40\n42\n");
+    }
+
+    #[test]
+    fn output_is_not_captured_by_default() {
+        let vm = Vm::new();
+
+        assert!(vm.captured_output().is_none());
+    }
+
+    #[test]
+    fn loops_and_function_calls() {
+        let src = "
+start:
+  mov ecx, 0
+loop:
+  call print
+  inc ecx
+  cmp ecx, 3
+  jl loop
+  jmp end
+print:
+  prn ecx
+  ret
+end:
+";
+        let mut vm = load_with(VmBuilder::new().capture_output(), src);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.captured_output().unwrap(), b"0\n1\n2\n");
+    }
+
+    #[test]
+    fn read_input_from_a_reader() {
+        let src = "
+start:
+  inp eax
+  cmp eax, -1
+  je end
+  prn eax
+  jmp start
+end:
+";
+        let mut vm =
+            load_with(VmBuilder::new().capture_output().input(&b"Hi"[..]), src);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.captured_output().unwrap(), b"72\n105\n");
+    }
+
+    #[test]
+    fn input_errors_are_propagated() {
+        struct Broken;
+
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(IoError::new(ErrorKind::Other, "broken"))
+            }
+        }
+
+        let mut vm = load_with(
+            VmBuilder::new().input(Broken),
+            "start:
+  inp eax
+",
+        );
+
+        match vm.run().unwrap_err().fault {
+            VmFault::Io(e) => assert_eq!(e.to_string(), "broken"),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn call_a_host_function() {
+        let src = "start:\n  mov eax, 7\n  mov ebx, 6\n  int 0x80\n";
+        let mut vm = load(src);
+        vm.register_host_call(0x80, |ctx| {
+            let product = ctx.reg(Register::Eax) * ctx.reg(Register::Ebx);
+            ctx.set_reg(Register::Eax, product);
+            ctx.memory_mut().write_i32(0, product)?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg(Register::Eax), 42);
+        assert_eq!(vm.memory().read_i32(0).unwrap(), 42);
+    }
+
+    #[test]
+    fn host_functions_can_abort_the_program() {
+        let mut vm = load("start:\n  int 1\n  mov eax, 1\n");
+        vm.register_host_call(1, |_| Err("Missing config value".into()));
+
+        match vm.run().unwrap_err().fault {
+            VmFault::Host { number, error } => {
+                assert_eq!(number, 1);
+                assert_eq!(error.to_string(), "Missing config value");
+            },
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        // the instruction after the int never ran
+        assert_eq!(vm.reg(Register::Eax), 0);
+        assert_eq!(vm.reg(Register::Eip), 0);
+    }
+
+    #[test]
+    fn host_functions_can_jump() {
+        let mut vm = load_with(
+            VmBuilder::new().capture_output(),
+            "start:\n  int 1\n  prn 1\nskip:\n  prn 2\n",
+        );
+        let skip = vm.label("skip").unwrap() as i32;
+        vm.register_host_call(1, move |ctx| {
+            ctx.set_reg(Register::Eip, skip);
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.captured_output().unwrap(), b"2\n");
+    }
+
+    #[test]
+    fn host_functions_cant_jump_outside_the_program() {
+        let mut vm = load("start:\n  int 1\n  nop\n");
+        vm.register_host_call(1, |ctx| {
+            ctx.set_reg(Register::Eip, 100);
+            Ok(())
+        });
+
+        match vm.run().unwrap_err().fault {
+            VmFault::InvalidJump { target } => assert_eq!(target, 100),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        assert_eq!(vm.reg(Register::Eip), 0);
+    }
+
+    #[test]
+    fn failed_host_functions_dont_jump() {
+        let mut vm = load("start:\n  int 1\n  nop\n");
+        vm.register_host_call(1, |ctx| {
+            ctx.set_reg(Register::Eax, 42);
+            ctx.set_reg(Register::Eip, 1);
+            Err("Oops".into())
+        });
+
+        match vm.run().unwrap_err().fault {
+            VmFault::Host { number, .. } => assert_eq!(number, 1),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        assert_eq!(vm.reg(Register::Eip), 0);
+        // writes made before the error are kept
+        assert_eq!(vm.reg(Register::Eax), 42);
+    }
+
+    #[test]
+    fn calling_an_unregistered_host_function_is_an_error() {
+        let mut vm = load("start:\n  int 2\n");
+        vm.register_host_call(2, |_| Ok(()));
+        assert!(vm.remove_host_call(2).is_some());
+
+        match vm.run().unwrap_err().fault {
+            VmFault::UnknownHostCall { number } => assert_eq!(number, 2),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn report_parse_errors() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"start:\n  frobnicate eax\n").unwrap();
+        let mut vm = Vm::new();
+
+        let err = vm.load(file.path()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Parsing failed: Line 2: unknown instruction, \"frobnicate\""
+        );
+        assert!(err.source().is_some());
+        match err {
+            LoadError::Parse(ParseError::UnknownInstruction { line, name }) => {
+                assert_eq!(line, 2);
+                assert_eq!(name, "frobnicate");
+            },
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn infinite_loops_run_out_of_fuel() {
+        let mut vm = load("start:\n  inc eax\n  jmp start\n");
+
+        let outcome = vm.run_with_fuel(11).unwrap();
+
+        assert_eq!(outcome, RunOutcome::OutOfFuel);
+        assert_eq!(vm.reg(Register::Eax), 6);
+        assert_eq!(vm.reg(Register::Eip), 1);
+    }
+
+    #[test]
+    fn resume_after_running_out_of_fuel() {
+        let src = "start:\n  prn 1\n  prn 2\n  prn 3\n  prn 4\n  prn 5\n";
+        let mut vm = load_with(VmBuilder::new().capture_output(), src);
+
+        assert_eq!(vm.run_with_fuel(2).unwrap(), RunOutcome::OutOfFuel);
+        assert_eq!(vm.captured_output().unwrap(), b"1\n2\n");
+        assert_eq!(vm.run_with_fuel(2).unwrap(), RunOutcome::OutOfFuel);
+        assert_eq!(vm.captured_output().unwrap(), b"1\n2\n3\n4\n");
+        vm.run().unwrap();
+        assert_eq!(vm.captured_output().unwrap(), b"1\n2\n3\n4\n5\n");
+
+        // once the program has halted, the next run starts from scratch
+        assert_eq!(vm.run_with_fuel(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(
+            vm.captured_output().unwrap(),
+            b"1\n2\n3\n4\n5\n1\n2\n3\n4\n5\n"
+        );
+    }
+
+    #[test]
+    fn exactly_enough_fuel_halts() {
+        let mut vm = load("start:\n  nop\n  nop\n");
+
+        assert_eq!(vm.run_with_fuel(2).unwrap(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn stop_at_a_deadline() {
+        let mut vm = load("start:\n  inc eax\n  jmp start\n");
+        let deadline = Instant::now() + Duration::from_millis(10);
+
+        let outcome = vm.run_until(deadline).unwrap();
+
+        assert_eq!(outcome, RunOutcome::DeadlineExceeded);
+        assert!(Instant::now() >= deadline);
+        // the VM is left in a consistent state which can be inspected
+        assert!(vm.reg(Register::Eax) > 0);
+    }
+
+    #[test]
+    fn cancel_from_another_thread() {
+        let mut vm = load("start:\n  inc eax\n  jmp start\n");
+        let token = vm.cancellation_token();
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            token.cancel();
+        });
+        let outcome = vm.run().unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(outcome, RunOutcome::Cancelled);
+    }
+
+    #[test]
+    fn share_a_cancellation_token_between_vms() {
+        let token = CancellationToken::new();
+        let builder = || VmBuilder::new().cancellation_token(token.clone());
+        let mut first = load_with(builder(), "start:\n  prn 1\n");
+        let mut second = load_with(builder(), "start:\n  prn 2\n");
+
+        token.cancel();
+
+        assert_eq!(first.run().unwrap(), RunOutcome::Cancelled);
+        assert_eq!(second.run().unwrap(), RunOutcome::Cancelled);
+        // nothing was executed
+        assert_eq!(first.reg(Register::Eip), 0);
+    }
+
+    #[test]
+    fn run_again_with_a_new_cancellation_token() {
+        let mut vm = load("start:\n  inc eax\n");
+        vm.cancellation_token().cancel();
+        assert_eq!(vm.run().unwrap(), RunOutcome::Cancelled);
+
+        vm.reset();
+        assert_eq!(vm.run().unwrap(), RunOutcome::Cancelled);
+
+        vm.set_cancellation_token(CancellationToken::new());
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+        assert_eq!(vm.reg(Register::Eax), 1);
+    }
+
+    fn fault(src: &str) -> RunError { load(src).run().unwrap_err() }
+
+    #[test]
+    fn divide_by_zero() {
+        let err = fault("start:\n  mov eax, 1\n  div eax, ebx\n");
+
+        assert!(matches!(err.fault, VmFault::DivideByZero));
+        assert_eq!(err.eip, 1);
+        assert_eq!(err.instruction, "div eax, ebx");
+        assert_eq!(err.line, 3);
+        assert!(matches!(fault("mod eax, 0").fault, VmFault::DivideByZero));
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let err = fault("start:\n  pop eax\n");
+
+        assert!(matches!(err.fault, VmFault::StackUnderflow { .. }));
+        assert!(matches!(fault("ret").fault, VmFault::StackUnderflow { .. }));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut vm = load_with(
+            VmBuilder::new().memory_size(64),
+            "start:\n  push eax\n  jmp start\n",
+        );
+
+        let err = vm.run().unwrap_err();
+
+        match err.fault {
+            VmFault::StackOverflow { esp } => assert_eq!(esp, 0),
+            other => panic!("Unexpected fault: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_memory_address() {
+        let mut vm = load_with(
+            VmBuilder::new().memory_size(64),
+            "start:\n  mov [16], 1\n",
+        );
+
+        match vm.run().unwrap_err().fault {
+            VmFault::InvalidAddress(e) => {
+                assert_eq!(e.address, 64);
+                assert_eq!(e.memory_size, 64);
+            },
+            other => panic!("Unexpected fault: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn faulting_pops_leave_the_stack_alone() {
+        let inputs = vec![
+            "start:\n  push 1\n  pop [1000]\n",
+            "start:\n  push 100\n  ret\n",
+        ];
+
+        for src in inputs {
+            let mut vm = load_with(VmBuilder::new().memory_size(1024), src);
+
+            let err = vm.run().unwrap_err();
+
+            assert_eq!(err.eip, 1, "{:?}", src);
+            assert_eq!(vm.reg(Register::Esp), 1020, "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn faulting_inp_leaves_the_input_alone() {
+        let mut vm = load_with(
+            VmBuilder::new().memory_size(1024).input(&b"A"[..]),
+            "start:\n  inp [1000]\n",
+        );
+        assert!(matches!(
+            vm.run().unwrap_err().fault,
+            VmFault::InvalidAddress(_)
+        ));
+
+        let program =
+            Program::parse("start:\n  inp eax\n", &Builtins::default());
+        vm.load_program(Arc::new(program.unwrap()));
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg(Register::Eax), i32::from(b'A'));
+    }
+
+    #[test]
+    fn invalid_jumps() {
+        let inputs = vec![
+            ("jmp -1", -1),
+            ("jmp 5", 5),
+            ("call 5", 5),
+            ("cmp eax, 0\nje 7", 7),
+        ];
+
+        for (src, target) in inputs {
+            match fault(src).fault {
+                VmFault::InvalidJump { target: t } => assert_eq!(t, target),
+                other => panic!("Unexpected fault for {:?}: {:?}", src, other),
+            }
+        }
+    }
+
+    #[test]
+    fn jumping_to_the_end_halts() {
+        let mut vm = load("start:\n  jmp end\n  prn 1\nend:\n");
+
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn faults_are_reported_with_context() {
+        let err = fault("start:\n  nop\n  div eax, 0\n");
+
+        assert_eq!(
+            err.to_string(),
+            "Divide by zero at instruction 1 (\"div eax, 0\", line 3)"
+        );
+    }
+
+    #[test]
+    fn next_eip_starts_at_the_entry_point() {
+        let mut vm = load("nop\nstart:\n  nop\n  nop\n");
+        assert_eq!(vm.reg(Register::Eip), 0);
+        assert_eq!(vm.next_eip(), 1);
+
+        vm.step();
+        assert_eq!(vm.next_eip(), 2);
+
+        vm.run().unwrap();
+        assert_eq!(vm.next_eip(), 1);
+    }
+
+    #[test]
+    fn step_through_a_program() {
+        let mut vm = load("nop\nstart:\n  mov eax, 1\n  add eax, 2\n");
+
+        assert!(matches!(vm.step(), StepStatus::Continued));
+        assert_eq!(vm.reg(Register::Eax), 1);
+        assert_eq!(vm.reg(Register::Eip), 2);
+
+        assert!(matches!(vm.step(), StepStatus::Halted));
+        assert_eq!(vm.reg(Register::Eax), 3);
+
+        // stepping again starts from the beginning
+        assert!(matches!(vm.step(), StepStatus::Continued));
+        assert_eq!(vm.reg(Register::Eax), 1);
+    }
+
+    #[test]
+    fn stepping_into_a_fault() {
+        let mut vm = load("start:\n  pop eax\n");
+
+        match vm.step() {
+            StepStatus::Faulted(e) => {
+                assert!(matches!(e.fault, VmFault::StackUnderflow { .. }))
+            },
+            other => panic!("Unexpected status: {:?}", other),
+        }
+    }
+
+    /// A reader which has no data available yet, like a non-blocking socket.
+    struct Trickle(Vec<u8>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop() {
+                Some(0) | None => Err(ErrorKind::WouldBlock.into()),
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn wait_for_input() {
+        // bytes are popped off the end, and 0 means "no data yet"
+        let input = Trickle(vec![b'b', 0, b'a', 0]);
+        let src = "start:\n  inp eax\n  prn eax\n  inp eax\n  prn eax\n";
+        let mut vm =
+            load_with(VmBuilder::new().capture_output().input(input), src);
+
+        assert!(matches!(vm.step(), StepStatus::WaitingForInput));
+        assert_eq!(vm.reg(Register::Eip), 0);
+        assert!(matches!(vm.step(), StepStatus::Continued));
+        assert!(matches!(vm.step(), StepStatus::Continued));
+        assert_eq!(vm.run().unwrap(), RunOutcome::WaitingForInput);
+        assert_eq!(vm.reg(Register::Eip), 2);
+        assert!(matches!(vm.run_until_halt(), StepStatus::Halted));
+
+        assert_eq!(vm.captured_output().unwrap(), b"97\n98\n");
+    }
+
+    #[test]
+    fn stop_at_breakpoints() {
+        let src = "start:\n  mov eax, 1\nloop:\n  add eax, eax\n  cmp eax, 8\n  jl loop\n";
+        let mut vm = load(src);
+        assert_eq!(vm.add_breakpoint_at_label("loop"), Some(1));
+        assert_eq!(vm.add_breakpoint_at_label("missing"), None);
+
+        let mut values = Vec::new();
+        while let RunOutcome::Breakpoint { eip } = vm.run().unwrap() {
+            assert_eq!(eip, 1);
+            values.push(vm.reg(Register::Eax));
+        }
+
+        assert_eq!(values, vec![1, 2, 4]);
+        assert_eq!(vm.reg(Register::Eax), 8);
+    }
+
+    #[test]
+    fn resume_from_a_breakpoint_after_running_out_of_fuel() {
+        let mut vm = load("start:\n  nop\n  inc eax\n");
+        vm.add_breakpoint(1);
+        assert_eq!(vm.run().unwrap(), RunOutcome::Breakpoint { eip: 1 });
+
+        assert_eq!(vm.run_with_fuel(0).unwrap(), RunOutcome::OutOfFuel);
+
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+        assert_eq!(vm.reg(Register::Eax), 1);
+    }
+
+    #[test]
+    fn breakpoints_can_be_removed() {
+        let mut vm = load("start:\n  nop\n  nop\n");
+        assert!(vm.add_breakpoint(1));
+        assert!(!vm.add_breakpoint(1));
+        assert_eq!(vm.breakpoints().collect::<Vec<_>>(), vec![1]);
+
+        assert!(vm.remove_breakpoint(1));
+
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn stop_when_a_watched_value_changes() {
+        let src =
+            "start:\n  mov eax, 1\n  mov [2], 5\n  mov [2], 5\n  mov [2], 6\n";
+        let mut vm = load(src);
+        assert!(vm.add_watchpoint(Watchpoint::Memory(8)));
+        assert!(!vm.add_watchpoint(Watchpoint::Memory(vm.memory_size())));
+
+        assert_eq!(
+            vm.run().unwrap(),
+            RunOutcome::Watchpoint {
+                watchpoint: Watchpoint::Memory(8),
+                eip: 1,
+                old_value: 0,
+                new_value: 5,
+            }
+        );
+        // writing the same value again doesn't count as a change
+        assert_eq!(
+            vm.run().unwrap(),
+            RunOutcome::Watchpoint {
+                watchpoint: Watchpoint::Memory(8),
+                eip: 3,
+                old_value: 5,
+                new_value: 6,
+            }
+        );
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn watch_registers() {
+        let mut vm = load("start:\n  mov ebx, 1\n  inc eax\n");
+        vm.add_watchpoint(Watchpoint::Register(Register::Eax));
+
+        match vm.run().unwrap() {
+            RunOutcome::Watchpoint { eip, new_value, .. } => {
+                assert_eq!(eip, 1);
+                assert_eq!(new_value, 1);
+            },
+            other => panic!("Unexpected outcome: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn report_every_value_an_instruction_changes() {
+        let mut vm = load("start:\n  push 7\n  pop eax\n");
+        vm.add_breakpoint(1);
+        assert_eq!(vm.run().unwrap(), RunOutcome::Breakpoint { eip: 1 });
+        let esp = vm.reg(Register::Esp);
+        vm.add_watchpoint(Watchpoint::Register(Register::Esp));
+        vm.add_watchpoint(Watchpoint::Register(Register::Eax));
+
+        assert_eq!(
+            vm.run().unwrap(),
+            RunOutcome::Watchpoint {
+                watchpoint: Watchpoint::Register(Register::Esp),
+                eip: 1,
+                old_value: esp,
+                new_value: esp + 4,
+            }
+        );
+        assert_eq!(
+            vm.run().unwrap(),
+            RunOutcome::Watchpoint {
+                watchpoint: Watchpoint::Register(Register::Eax),
+                eip: 1,
+                old_value: 0,
+                new_value: 7,
+            }
+        );
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    }
+
+    /// A writer which can still be read after it's been given to the VM.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn trace_executed_instructions() {
+        let trace = SharedBuffer::default();
+        let mut vm = load_with(
+            VmBuilder::new()
+                .capture_output()
+                .trace_to(trace.clone(), TraceFormat::Human),
+            "start:\n  mov eax, 2\n  mov [1], eax\n  prn [1]\n",
+        );
+
+        vm.run().unwrap();
+
+        let got = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            got,
+            "   0: mov eax, 2 | 0, 2 | eax: 0 -> 2\n   1: mov [1], eax | 0, 2\n   2: prn [1] | 2\n"
+        );
+    }
+
+    #[test]
+    fn json_lines_trace() {
+        let trace = SharedBuffer::default();
+        let mut vm = load("start:\n  push 7\n  pop ebx\n");
+        vm.trace_to(trace.clone(), TraceFormat::JsonLines);
+
+        vm.run().unwrap();
+        vm.stop_tracing().unwrap();
+        vm.run().unwrap();
+
+        let got = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<serde_json::Value> = got
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["mnemonic"], "pop");
+        assert_eq!(records[1]["line"], 3);
+        assert_eq!(records[1]["changes"]["ebx"], serde_json::json!([0, 7]));
+    }
+
+    #[test]
+    fn json_lines_trace_uses_the_original_source_location() {
+        let mut library = NamedTempFile::new().unwrap();
+        library.write_all(b"  push 7\n").unwrap();
+        let library = library.path().display().to_string();
+        let mut main = NamedTempFile::new().unwrap();
+        write!(main, "start:\n%include {}\n  pop ebx\n", library).unwrap();
+        let main_file = main.path().display().to_string();
+        let trace = SharedBuffer::default();
+        let mut vm = VmBuilder::new()
+            .trace_to(trace.clone(), TraceFormat::JsonLines)
+            .build()
+            .unwrap();
+        vm.load(main.path()).unwrap();
+
+        vm.run().unwrap();
+
+        let got = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<serde_json::Value> = got
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records[0]["file"], library);
+        assert_eq!(records[0]["line"], 1);
+        assert_eq!(records[1]["file"], main_file);
+        assert_eq!(records[1]["line"], 3);
+    }
+
+    #[test]
+    fn trace_faulting_instructions() {
+        let trace = SharedBuffer::default();
+        let mut vm = load("start:\n  mov eax, 1\n  div eax, 0\n");
+        vm.trace_to(trace.clone(), TraceFormat::Human);
+
+        vm.run().unwrap_err();
+
+        let got = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            got,
+            "   0: mov eax, 1 | 0, 1 | eax: 0 -> 1\n   1: div eax, 0 | 1, 0 | fault: Divide by zero\n"
+        );
+    }
+
+    #[test]
+    fn trace_errors_dont_stop_the_program() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(IoError::new(ErrorKind::Other, "broken"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let mut vm = load_with(
+            VmBuilder::new().capture_output(),
+            "start:\n  prn 1\n  prn 2\n",
+        );
+        vm.trace_to(Broken, TraceFormat::Human);
+
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+
+        assert_eq!(vm.captured_output().unwrap(), b"1\n2\n");
+        assert_eq!(vm.trace_error().unwrap().to_string(), "broken");
+        assert_eq!(vm.stop_tracing().unwrap_err().to_string(), "broken");
+        assert!(vm.trace_error().is_none());
+    }
+
+    #[test]
+    fn profile_a_loop() {
+        let src = "start:\n  mov ecx, 3\nloop:\n  dec ecx\n  cmp ecx, 0\n  \
+                   jne loop\n";
+        let mut vm = load(src);
+        assert!(vm.profile().is_none());
+        vm.start_profiling();
+
+        vm.run().unwrap();
+
+        let profile = vm.stop_profiling().unwrap();
+        assert_eq!(profile.total(), 10);
+        assert_eq!(profile.count(0), 1);
+        assert_eq!(profile.count(3), 3);
+        assert_eq!(profile.regions()[0].label.as_deref(), Some("loop"));
+        assert_eq!(profile.regions()[0].count, 9);
+        assert!(vm.profile().is_none());
+    }
+
+    #[test]
+    fn record_coverage_for_the_loaded_file() {
+        let src = "start:\n  cmp eax, 1\n  je skip\n  prn eax\nskip:\n";
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(src.as_bytes()).unwrap();
+        let mut vm = VmBuilder::new().capture_output().build().unwrap();
+        vm.start_coverage();
+        vm.load(file.path()).unwrap();
+
+        vm.run().unwrap();
+
+        let coverage = vm.coverage().unwrap();
+        assert_eq!(coverage.branch(1).unwrap().not_taken, 1);
+        let lines = coverage.lines();
+        let name = file.path().display().to_string();
+        assert_eq!(lines[&crate::SourceLocation::new(name.as_str(), 4)], 1);
+        let mut lcov = Vec::new();
+        coverage.write_lcov(&mut lcov).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.contains(&format!("SF:{}\n", name)));
+        assert!(lcov.contains("BRDA:3,1,0,0\nBRDA:3,1,1,1\n"));
+    }
+
+    const COUNTDOWN: &str = "start:\n  mov ecx, 50\nloop:\n  mov [1], ecx\n  \
+                             add eax, [1]\n  push eax\n  pop ebx\n  \
+                             prn eax\n  dec ecx\n  cmp ecx, 0\n  jg loop\n";
+
+    #[test]
+    fn resuming_a_snapshot_matches_an_uninterrupted_run() {
+        let builder = || VmBuilder::new().memory_size(1024).capture_output();
+        let mut uninterrupted = load_with(builder(), COUNTDOWN);
+        uninterrupted.run().unwrap();
+
+        for &fuel in &[1, 17, 100, 250] {
+            let mut first = load_with(builder(), COUNTDOWN);
+            assert_eq!(
+                first.run_with_fuel(fuel).unwrap(),
+                RunOutcome::OutOfFuel
+            );
+            let bytes = first.snapshot().to_bytes();
+
+            let mut second = load_with(builder(), COUNTDOWN);
+            second
+                .restore(&Snapshot::from_bytes(&bytes).unwrap())
+                .unwrap();
+            assert_eq!(second.run().unwrap(), RunOutcome::Halted);
+
+            let mut output = first.take_captured_output().unwrap();
+            output.extend(second.take_captured_output().unwrap());
+            assert_eq!(output, uninterrupted.captured_output().unwrap());
+            assert_eq!(second.cpu, uninterrupted.cpu);
+        }
+    }
+
+    #[test]
+    fn snapshots_only_restore_into_the_same_program() {
+        let vm = load(COUNTDOWN);
+        let snapshot = vm.snapshot();
+        let mut other = load("start:\n  nop\n");
+
+        let err = other.restore(&snapshot).unwrap_err();
+
+        assert!(matches!(err, SnapshotError::ProgramMismatch));
+    }
+
+    #[test]
+    fn forks_share_memory_until_it_is_written() {
+        let mut setup = load_with(
+            VmBuilder::new().memory_size(64 * 1024).capture_output(),
+            "start:\n  prn [4096]\n  mov [4096], eax\n",
+        );
+        setup.memory_mut().write_i32(0, 1).unwrap();
+        setup.memory_mut().write_i32(4 * 4096, 7).unwrap();
+        setup.set_reg(Register::Eax, 5);
+
+        let mut fork = setup.fork();
+        assert_eq!(fork.cpu.memory.shared_pages(&setup.cpu.memory), 2);
+        assert!(Arc::ptr_eq(&fork.program, &setup.program));
+
+        fork.run().unwrap();
+
+        assert_eq!(fork.captured_output().unwrap(), b"7\n");
+        assert_eq!(fork.memory().read_i32(4 * 4096).unwrap(), 5);
+        assert_eq!(setup.memory().read_i32(4 * 4096).unwrap(), 7);
+        // only the page which was written to was copied
+        assert_eq!(fork.cpu.memory.shared_pages(&setup.cpu.memory), 1);
+        assert_eq!(setup.captured_output().unwrap(), b"");
+    }
+
+    #[test]
+    fn forks_resume_a_run_in_progress() {
+        let mut vm = load_with(
+            VmBuilder::new().memory_size(1024).capture_output(),
+            COUNTDOWN,
+        );
+        assert_eq!(vm.run_with_fuel(100).unwrap(), RunOutcome::OutOfFuel);
+        vm.take_captured_output();
+
+        let mut fork = vm.fork();
+        fork.run().unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(fork.cpu, vm.cpu);
+        assert_eq!(fork.captured_output(), vm.captured_output());
+    }
+
+    #[test]
+    fn reset_and_run_again() {
+        let mut vm = load_with(
+            VmBuilder::new().capture_output(),
+            "start:\n  add [0], 5\n  push [0]\n  pop eax\n  prn eax\n",
+        );
+        vm.run().unwrap();
+        let first_run = vm.cpu.clone();
+
+        vm.reset();
+        assert_eq!(vm.memory().read_i32(0).unwrap(), 0);
+        assert_eq!(vm.reg(Register::Eax), 0);
+        vm.run().unwrap();
+
+        assert_eq!(vm.cpu, first_run);
+        assert_eq!(vm.captured_output().unwrap(), b"5\n5\n");
+    }
+
+    #[test]
+    fn share_a_program_between_vms() {
+        let builtins = Builtins {
+            memory_size: 64,
+            ..Builtins::default()
+        };
+        let program = Arc::new(
+            Program::parse("start:\n  prn __TVM_MEM_SIZE__\n", &builtins)
+                .unwrap(),
+        );
+
+        let outputs: Vec<_> = (0..3)
+            .map(|_| {
+                let mut vm = VmBuilder::new()
+                    .memory_size(64)
+                    .capture_output()
+                    .build()
+                    .unwrap();
+                vm.load_program(Arc::clone(&program));
+                vm.run().unwrap();
+                vm.take_captured_output().unwrap()
+            })
+            .collect();
+
+        assert_eq!(outputs, vec![b"64\n".to_vec(); 3]);
+        assert_eq!(Arc::strong_count(&program), 1);
+    }
+
+    #[test]
+    fn run_a_vm_on_another_thread() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Vm>();
+
+        let mut vm = load_with(
+            VmBuilder::new().capture_output(),
+            "start:\n  int 1\n  prn eax\n",
+        );
+        vm.register_host_call(1, |ctx| {
+            ctx.set_reg(Register::Eax, 42);
+            Ok(())
+        });
+
+        let output = thread::spawn(move || {
+            vm.run().unwrap();
+            vm.take_captured_output().unwrap()
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(output, b"42\n");
+    }
+}